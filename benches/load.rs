@@ -0,0 +1,65 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::hint::black_box;
+use std::io::{BufReader, Cursor};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use kknd2_mapview::map::{load_map_from_reader, LoadOptions};
+use kknd2_mapview::test_support::{raw_map, TestLayer};
+use kknd2_mapview::viewport::{visible_tile_range, Dimensions};
+
+const TILE_SIZE: u32 = 32;
+const MAP_SIZE: u32 = 128;
+const UNIQUE_TILES: u32 = 256;
+const PALETTE_SIZE: u32 = 256;
+
+// A single layer map of MAP_SIZE x MAP_SIZE cells cycling through UNIQUE_TILES tiles
+fn synthetic_map() -> Vec<u8> {
+    let tile_bytes = TILE_SIZE * TILE_SIZE;
+    let palette: Vec<u16> = (0..PALETTE_SIZE).map(|i| (i * 0x7f) as u16 & 0x7fff).collect();
+    let cells: Vec<u32> = (0..MAP_SIZE * MAP_SIZE).map(|i| i % UNIQUE_TILES + 1).collect();
+    let tiles: Vec<Vec<u8>> = (0..UNIQUE_TILES)
+        .map(|tile| (0..tile_bytes).map(|i| ((tile * tile_bytes + i) * 7 % PALETTE_SIZE) as u8).collect())
+        .collect();
+    let tiles: Vec<&[u8]> = tiles.iter().map(Vec::as_slice).collect();
+
+    let layer = TestLayer { tile_size: (TILE_SIZE, TILE_SIZE), map_size: (MAP_SIZE, MAP_SIZE), cells: &cells };
+    raw_map(&palette, &[layer], &tiles)
+}
+
+fn bench_load_map(c: &mut Criterion) {
+    let data = synthetic_map();
+    let options = LoadOptions::default();
+
+    c.bench_function("load_map", |b| {
+        b.iter(|| {
+            let mut reader = BufReader::new(Cursor::new(black_box(data.as_slice())));
+            load_map_from_reader(&mut reader, &options).unwrap()
+        })
+    });
+}
+
+fn bench_visible_tile_range(c: &mut Criterion) {
+    let map_pixels = (MAP_SIZE * TILE_SIZE) as i32;
+
+    c.bench_function("visible_tile_range", |b| {
+        let mut offset = 0;
+        b.iter(|| {
+            // Walk diagonally across the map so each frame has a different partial tile offset
+            offset = (offset + 17) % map_pixels;
+            visible_tile_range(
+                black_box((offset, offset)),
+                black_box(Dimensions::new(1024, 768)),
+                Dimensions::new(MAP_SIZE, MAP_SIZE),
+                Dimensions::new(TILE_SIZE, TILE_SIZE),
+            )
+        })
+    });
+}
+
+criterion_group!(benches, bench_load_map, bench_visible_tile_range);
+criterion_main!(benches);
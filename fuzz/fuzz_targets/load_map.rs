@@ -0,0 +1,19 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+#![no_main]
+
+use std::io::{BufReader, Cursor};
+
+use kknd2_mapview::map::{load_map_from_reader, LoadOptions};
+use libfuzzer_sys::fuzz_target;
+
+// Everything a map file can be: raw MAPD data, or anything else read as a compressed
+// level archive, which the parse_map target never reaches. Seeds can be .lps and
+// .lpc files as well as extracted maps. Any input must produce Ok or Err, never a panic.
+fuzz_target!(|data: &[u8]| {
+    let mut reader = BufReader::new(Cursor::new(data));
+    let _ = load_map_from_reader(&mut reader, &LoadOptions::default());
+});
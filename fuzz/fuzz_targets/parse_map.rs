@@ -0,0 +1,27 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+#![no_main]
+
+use std::io::{BufReader, Cursor};
+
+use kknd2_mapview::map::{parse_map, LoadOptions};
+use libfuzzer_sys::fuzz_target;
+
+// Inputs are laid out like a raw MAPD file (magic, base_offset, map data) so
+// extracted maps can be used as seeds. Any input must produce Ok or Err, never a panic.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+
+    let base_offset = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let mut reader = BufReader::new(Cursor::new(data));
+    if reader.seek_relative(8).is_err() {
+        return;
+    }
+
+    let _ = parse_map(&mut reader, base_offset, &LoadOptions::default());
+});
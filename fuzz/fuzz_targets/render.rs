@@ -0,0 +1,89 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+#![no_main]
+
+use std::io::{BufReader, Cursor};
+
+use kknd2_mapview::map::{load_map_from_reader, LoadOptions};
+use kknd2_mapview::render::{render_map, RenderOptions};
+use kknd2_mapview::test_support::{raw_map, TestLayer};
+use libfuzzer_sys::fuzz_target;
+
+const MAX_LAYERS: usize = 3;
+const MAX_TILE_SIZE: u32 = 8;
+const MAX_MAP_SIZE: u32 = 4;
+const PALETTE_SIZE: usize = 16;
+
+// Reads the input from the front, with 0 once it runs out
+struct Input<'a> {
+    bytes: &'a [u8],
+}
+
+impl Input<'_> {
+    fn byte(&mut self) -> u8 {
+        let (first, rest) = self.bytes.split_first().unwrap_or((&0, &[]));
+        self.bytes = rest;
+        *first
+    }
+
+    // From 1 to max
+    fn size(&mut self, max: u32) -> u32 {
+        self.byte() as u32 % max + 1
+    }
+}
+
+// The input picks the layer count, each layer's tile and map size, the palette, which
+// tile each cell shows and the tiles' palette indices, so layers of mixed sizes,
+// shared and empty cells and partly covered maps all come up. Exact pixels are left
+// to tests/render.rs, this checks that any map that loads renders at its pixel size
+fuzz_target!(|data: &[u8]| {
+    let mut input = Input { bytes: data };
+
+    let layer_count = input.size(MAX_LAYERS as u32) as usize;
+    let sizes: Vec<((u32, u32), (u32, u32))> = (0..layer_count)
+        .map(|_| {
+            let tile_size = (input.size(MAX_TILE_SIZE), input.size(MAX_TILE_SIZE));
+            (tile_size, (input.size(MAX_MAP_SIZE), input.size(MAX_MAP_SIZE)))
+        })
+        .collect();
+    let palette: Vec<u16> = (0..PALETTE_SIZE).map(|_| u16::from_le_bytes([input.byte(), input.byte()])).collect();
+
+    // Every cell has a tile of its own to point at, sized for its layer
+    let mut tiles = Vec::<Vec<u8>>::new();
+    let mut cells = Vec::<Vec<u32>>::new();
+    for ((tile_width, tile_height), (map_width, map_height)) in &sizes {
+        let first_tile = tiles.len() as u32 + 1;
+        let count = map_width * map_height;
+        let layer_cells = (0..count).map(|_| match input.byte() as u32 % (count + 1) {
+            0 => 0,
+            n => first_tile + n - 1,
+        });
+        cells.push(layer_cells.collect());
+        for _ in 0..count {
+            tiles.push((0..tile_width * tile_height).map(|_| input.byte()).collect());
+        }
+    }
+
+    let layers: Vec<TestLayer> = sizes
+        .iter()
+        .zip(&cells)
+        .map(|((tile_size, map_size), cells)| TestLayer { tile_size: *tile_size, map_size: *map_size, cells })
+        .collect();
+    let tiles: Vec<&[u8]> = tiles.iter().map(Vec::as_slice).collect();
+    let data = raw_map(&palette, &layers, &tiles);
+
+    let mut reader = BufReader::new(Cursor::new(data));
+    let map = load_map_from_reader(&mut reader, &LoadOptions::default()).expect("a synthetic map should load");
+    let background = input.byte() % 2 == 1;
+    let options = RenderOptions { background: background.then_some((1, 2, 3)) };
+    let image = render_map(&map, &options).expect("a synthetic map should render");
+
+    let size = map.pixel_size();
+    assert_eq!(image.dimensions(), (size.width, size.height));
+    if background {
+        assert!(image.pixels().all(|pixel| pixel.0[3] == 0xff), "the background should make every pixel opaque");
+    }
+});
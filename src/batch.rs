@@ -0,0 +1,329 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::DynamicImage;
+use log::warn;
+
+use kknd2_mapview::map::{
+    is_supported_extension, load_map, LoadOptions, DEFAULT_UNIFORM_THRESHOLD, LAYER_MAGIC, MAPD_KIND, MAP_MAGIC,
+    SUPPORTED_EXTENSIONS,
+};
+use kknd2_mapview::unpack::fourcc_to_string;
+use kknd2_mapview::palette::{load_palette, save_palette, Colour};
+use kknd2_mapview::render::{render_thumbnail, RenderOptions};
+
+pub const THUMBNAIL_WIDTH: u32 = 256;
+// Problems listed per map by --validate-dir, a broken tile can be referenced by every cell
+const MAX_LISTED_PROBLEMS: usize = 5;
+
+pub fn list_maps(input_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let entries = fs::read_dir(input_dir)
+        .map_err(|e| format!("Failed to read directory {:?}: {}", input_dir, e))?;
+
+    let mut paths = Vec::<PathBuf>::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && is_supported_extension(&path) {
+            paths.push(path);
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+// Output files keep the full source name so that e.g. level.lps and level.lpc don't collide
+fn output_path(output_dir: &Path, source: &Path) -> PathBuf {
+    let mut file_name = source.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".png");
+    output_dir.join(file_name)
+}
+
+// What a thumbnail was generated from, recorded in the manifest
+struct MapSummary {
+    width: u32,
+    height: u32,
+    layers: usize,
+    // Each layer's data_offset and data_len
+    layer_ranges: Vec<(u64, u64)>,
+}
+
+fn generate_thumbnail(
+    source: &Path,
+    output: &Path,
+    width: u32,
+    options: &RenderOptions,
+) -> Result<MapSummary, Box<dyn Error>> {
+    let map = load_map(source, &LoadOptions::default())?;
+    let thumbnail = render_thumbnail(&map, width, options)?;
+
+    // With a background every pixel is opaque, so the alpha channel can be dropped
+    let saved = match options.background {
+        None => thumbnail.save(output),
+        Some(_) => DynamicImage::ImageRgba8(thumbnail).to_rgb8().save(output),
+    };
+    saved.map_err(|e| format!("Failed to write {:?}: {}", output, e))?;
+
+    let layer = map.layers.first().ok_or("Map has no layers")?;
+    Ok(MapSummary {
+        width: layer.map_size.width,
+        height: layer.map_size.height,
+        layers: map.layers.len(),
+        layer_ranges: map.layers.iter().map(|layer| (layer.data_offset, layer.data_len)).collect(),
+    })
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// One manifest entry, dimensions are of the first layer in tiles as with --dimensions
+fn manifest_entry(source: &Path, output: &Path, result: &Result<MapSummary, Box<dyn Error>>) -> String {
+    let source = json_string(&source.to_string_lossy());
+    match result {
+        Ok(summary) => format!(
+            "{{\"source\": {}, \"output\": {}, \"width\": {}, \"height\": {}, \"layers\": {}, \"layer_ranges\": [{}], \"ok\": true}}",
+            source,
+            json_string(&output.to_string_lossy()),
+            summary.width,
+            summary.height,
+            summary.layers,
+            summary
+                .layer_ranges
+                .iter()
+                .map(|(offset, len)| format!("{{\"offset\": {}, \"length\": {}}}", offset, len))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Err(e) => format!(
+            "{{\"source\": {}, \"ok\": false, \"error\": {}}}",
+            source,
+            json_string(&e.to_string())
+        ),
+    }
+}
+
+pub fn print_dimensions(path: &Path) -> Result<(), Box<dyn Error>> {
+    let map = load_map(path, &LoadOptions::default())?;
+    let layer = map.layers.first().ok_or("Map has no layers")?;
+    println!("{}x{}", layer.map_size.width, layer.map_size.height);
+    Ok(())
+}
+
+// What the parser recognises, to help work out why a file isn't accepted
+pub fn print_version() {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    println!("Raw map magic: {:#x}, other files are read as compressed level archives", MAP_MAGIC);
+    println!("Archive chunk: {:#x} ({})", MAPD_KIND, fourcc_to_string(MAPD_KIND));
+    println!("Layer magic: {:#x} ({})", LAYER_MAGIC, fourcc_to_string(LAYER_MAGIC));
+    println!("Extensions: {}", SUPPORTED_EXTENSIONS.join(", "));
+}
+
+// Prints how often each tile id is used in the first layer as CSV, most used first
+pub fn print_histogram(path: &Path) -> Result<(), Box<dyn Error>> {
+    let map = load_map(path, &LoadOptions::default())?;
+    let layer = map.layers.first().ok_or("Map has no layers")?;
+
+    let mut usage: Vec<(u32, u32)> = layer.tile_usage().into_iter().collect();
+    usage.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    println!("id,count");
+    for (tile_id, count) in usage {
+        println!("{},{}", tile_id, count);
+    }
+    Ok(())
+}
+
+pub fn export_palette(path: &Path, output: &Path) -> Result<(), Box<dyn Error>> {
+    let map = load_map(path, &LoadOptions::default())?;
+    save_palette(output, &map.palette)?;
+    println!("{} -> {}", path.display(), output.display());
+    Ok(())
+}
+
+// A map's embedded palette, or the colours of a palette file
+fn read_palette_from(path: &Path) -> Result<Vec<Colour>, Box<dyn Error>> {
+    if is_supported_extension(path) {
+        Ok(load_map(path, &LoadOptions::default())?.palette)
+    } else {
+        load_palette(path)
+    }
+}
+
+fn describe_colour(colour: Option<&Colour>) -> String {
+    match colour {
+        None => "missing".to_string(),
+        Some(colour) if colour.a == 0 => format!("#{:02x}{:02x}{:02x} (transparent)", colour.r, colour.g, colour.b),
+        Some(colour) => format!("#{:02x}{:02x}{:02x}", colour.r, colour.g, colour.b),
+    }
+}
+
+// Prints every palette index whose colour differs between two maps or palette files
+pub fn diff_palettes(a: &Path, b: &Path) -> Result<(), Box<dyn Error>> {
+    let palette_a = read_palette_from(a)?;
+    let palette_b = read_palette_from(b)?;
+
+    if palette_a.len() != palette_b.len() {
+        println!("Palette sizes differ: {} has {}, {} has {}", a.display(), palette_a.len(), b.display(), palette_b.len());
+    }
+
+    let mut changed = 0;
+    for index in 0..palette_a.len().max(palette_b.len()) {
+        let (colour_a, colour_b) = (palette_a.get(index), palette_b.get(index));
+        if colour_a != colour_b {
+            println!("{:3}: {} -> {}", index, describe_colour(colour_a), describe_colour(colour_b));
+            changed += 1;
+        }
+    }
+
+    println!("{} entries differ", changed);
+    Ok(())
+}
+
+pub fn generate_thumbnails(
+    input_dir: &Path,
+    output_dir: &Path,
+    width: u32,
+    options: &RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(output_dir)
+        .map_err(|e| format!("Failed to create directory {:?}: {}", output_dir, e))?;
+
+    let mut entries = Vec::<String>::new();
+    for source in list_maps(input_dir)? {
+        let output = output_path(output_dir, &source);
+
+        let result = generate_thumbnail(&source, &output, width, options);
+        match &result {
+            Ok(_) => println!("{} -> {}", source.display(), output.display()),
+            Err(e) => warn!("Skipping {}: {}", source.display(), e),
+        }
+        entries.push(manifest_entry(&source, &output, &result));
+    }
+
+    // Describes the output for tools like gallery generators, including the maps that failed
+    let manifest_path = output_dir.join("manifest.json");
+    let manifest = if entries.is_empty() {
+        "[]\n".to_string()
+    } else {
+        format!("[\n  {}\n]\n", entries.join(",\n  "))
+    };
+    fs::write(&manifest_path, manifest).map_err(|e| format!("Failed to write {:?}: {}", manifest_path, e))?;
+
+    Ok(())
+}
+
+// Outcome of checking one map. Warnings are heuristics that don't fail the run
+enum Check {
+    Pass(String),
+    Warn(Vec<String>),
+    Fail(Vec<String>),
+}
+
+fn check_map(path: &Path) -> Check {
+    // Truncated tiles are loaded as failed tiles so validate reports them alongside
+    // everything else. The uniform check is done below, so the load doesn't also log it
+    let options = LoadOptions { allow_truncated: true, uniform_threshold: Some(1.0), ..LoadOptions::default() };
+    let map = match load_map(path, &options) {
+        Ok(map) => map,
+        Err(e) => return Check::Fail(vec![e.to_string()]),
+    };
+
+    let problems = map.validate();
+    if !problems.is_empty() {
+        return Check::Fail(problems);
+    }
+
+    let warnings: Vec<String> = map
+        .layers
+        .iter()
+        .enumerate()
+        .filter_map(|(l, layer)| {
+            let (tile_index, fraction) = layer.uniform_tile(DEFAULT_UNIFORM_THRESHOLD)?;
+            Some(format!("Layer {}: {:.1}% of cells are tile {:#x}", l, fraction * 100.0, tile_index))
+        })
+        .collect();
+    if !warnings.is_empty() {
+        return Check::Warn(warnings);
+    }
+
+    let layer = &map.layers[0];
+    Check::Pass(format!(
+        "{} {}, {}x{} tiles",
+        map.layers.len(),
+        if map.layers.len() == 1 { "layer" } else { "layers" },
+        layer.map_size.width,
+        layer.map_size.height
+    ))
+}
+
+// Checks every map in a directory and prints a line per map and a summary. Returns
+// false if any map failed, for the exit status
+pub fn validate_directory(input_dir: &Path) -> Result<bool, Box<dyn Error>> {
+    let paths = list_maps(input_dir)?;
+    let names: Vec<String> = paths
+        .iter()
+        .map(|path| path.file_name().unwrap_or_default().to_string_lossy().into_owned())
+        .collect();
+    let name_width = names.iter().map(|name| name.chars().count()).max().unwrap_or(0);
+
+    let (mut passed, mut warned, mut failed) = (0, 0, 0);
+    for (path, name) in paths.iter().zip(&names) {
+        let (status, mut details) = match check_map(path) {
+            Check::Pass(summary) => {
+                passed += 1;
+                ("PASS", vec![summary])
+            }
+            Check::Warn(warnings) => {
+                warned += 1;
+                ("WARN", warnings)
+            }
+            Check::Fail(problems) => {
+                failed += 1;
+                ("FAIL", problems)
+            }
+        };
+
+        if details.len() > MAX_LISTED_PROBLEMS {
+            let more = details.len() - MAX_LISTED_PROBLEMS;
+            details.truncate(MAX_LISTED_PROBLEMS);
+            details.push(format!("... and {} more", more));
+        }
+
+        // A map's further lines are indented under its name
+        for (i, detail) in details.iter().enumerate() {
+            let name = if i == 0 { name.as_str() } else { "" };
+            let status = if i == 0 { status } else { "" };
+            println!("{:<4}  {:<name_width$}  {}", status, name, detail);
+        }
+    }
+
+    println!(
+        "{} maps: {} passed, {} with warnings, {} failed",
+        paths.len(),
+        passed,
+        warned,
+        failed
+    );
+    Ok(failed == 0)
+}
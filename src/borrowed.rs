@@ -0,0 +1,170 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::map::{
+    data_position, read_layer_header, read_map_header, read_tile_map, tile_position, LayerHeader, MapHeader, MAP_MAGIC,
+};
+use crate::palette::Colour;
+use crate::viewport::Dimensions;
+
+// Read-only view of raw MAPD data. Tile palette indices are borrowed out of the
+// buffer rather than copied, and nothing is decoded to RGBA, for analysis tools that
+// walk many maps. The viewer uses the owned Map from load_map
+pub struct MapRef<'a> {
+    // As stored in the file, palette overrides don't apply
+    pub palette: Vec<Colour>,
+    pub layers: Vec<LayerRef<'a>>,
+}
+
+pub struct LayerRef<'a> {
+    // In tiles
+    pub map_size: Dimensions,
+    // In pixels
+    pub tile_size: Dimensions,
+    pub tile_map: Vec<u32>,
+    // Palette indices of each unique tile, keyed by tile id
+    pub tiles: HashMap<u32, &'a [u8]>,
+}
+
+impl LayerRef<'_> {
+    // Tile id of the cell at (x, y) in tiles, 0 for an empty cell, or None outside the map
+    pub fn tile_at(&self, x: u32, y: u32) -> Option<u32> {
+        if !self.map_size.contains(x, y) {
+            return None;
+        }
+        self.tile_map.get(self.map_size.index_of(x, y)).copied()
+    }
+}
+
+fn read_layer<'a>(
+    data: &'a [u8],
+    index: usize,
+    position: u64,
+    base_offset: u32,
+) -> Result<LayerRef<'a>, Box<dyn Error>> {
+    let data_length = data.len() as u64;
+    let mut reader = Cursor::new(data);
+    reader.set_position(position);
+
+    let LayerHeader { map_size, tile_size } = read_layer_header(&mut reader, index, position, None)?;
+    let remaining = data_length.saturating_sub(reader.position());
+    let tile_map = read_tile_map(&mut reader, map_size, remaining)?;
+
+    let tile_length = tile_size.area();
+    let mut tiles = HashMap::<u32, &'a [u8]>::new();
+    for tile_id in tile_map.iter().copied().filter(|tile_id| *tile_id != 0) {
+        if let Entry::Vacant(entry) = tiles.entry(tile_id) {
+            // In range of data, which is in memory, so the conversions to usize can't truncate
+            let position = tile_position(tile_id, base_offset, data_length, tile_length)? as usize;
+            entry.insert(&data[position..position + tile_length as usize]);
+        }
+    }
+
+    Ok(LayerRef { map_size, tile_size, tile_map, tiles })
+}
+
+// Parses a raw MAPD buffer, as saved by save_map or extracted from a level archive.
+// Compressed archives have to be decompressed into a buffer of their own first
+pub fn parse_map_ref(data: &[u8]) -> Result<MapRef<'_>, Box<dyn Error>> {
+    let mut reader = Cursor::new(data);
+
+    let magic = reader.read_u32::<LittleEndian>()?;
+    if magic != MAP_MAGIC {
+        return Err(format!("Expected raw MAPD data starting with {:#x}, found {:#x}", MAP_MAGIC, magic).into());
+    }
+    let base_offset = reader.read_u32::<LittleEndian>()?;
+
+    let MapHeader { layer_offsets, palette } = read_map_header(&mut reader)?;
+    let palette = palette.into_iter().map(Colour::from_packed).collect();
+
+    let mut map_layers = Vec::<LayerRef>::with_capacity(layer_offsets.len());
+    for (i, layer_offset) in layer_offsets.into_iter().enumerate() {
+        let position = data_position(layer_offset, base_offset, data.len() as u64)?;
+        map_layers.push(read_layer(data, i, position, base_offset)?);
+    }
+
+    Ok(MapRef { palette, layers: map_layers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{load_map_from_reader, LoadOptions, Map, MapError};
+    use crate::test_support::{raw_map, TestLayer};
+    use std::io::BufReader;
+
+    fn load(data: &[u8]) -> Result<Map, Box<dyn Error>> {
+        load_map_from_reader(&mut BufReader::new(Cursor::new(data)), &LoadOptions::default())
+    }
+
+    fn assert_same_as_owned(data: &[u8]) {
+        let owned = load(data).unwrap();
+        let borrowed = parse_map_ref(data).unwrap();
+
+        assert_eq!(borrowed.palette, owned.palette);
+        assert_eq!(borrowed.layers.len(), owned.layers.len());
+        for (borrowed, owned) in borrowed.layers.iter().zip(&owned.layers) {
+            assert_eq!(borrowed.map_size, owned.map_size);
+            assert_eq!(borrowed.tile_size, owned.tile_size);
+            assert_eq!(borrowed.tile_map, owned.tile_map);
+            assert_eq!(borrowed.tiles.len(), owned.tiles.len());
+            for (tile_id, tile) in &owned.tiles {
+                assert_eq!(borrowed.tiles[tile_id], tile.indices.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn borrowed_and_owned_parsers_read_the_same_map() {
+        let palette = [0, 0x7c00, 0x03e0, 0x001f, 0x7fff];
+        let tiles: [&[u8]; 4] = [&[1, 2, 3, 4], &[4, 3, 2, 1], &[0, 1, 0, 1], &[1, 1, 2, 2, 3, 3, 4, 4]];
+
+        let one_layer = [TestLayer { tile_size: (2, 2), map_size: (2, 2), cells: &[1, 2, 0, 1] }];
+        assert_same_as_owned(&raw_map(&palette, &one_layer, &tiles[..2]));
+
+        // Mixed tile sizes, empty cells and a tile used more than once
+        let two_layers = [
+            TestLayer { tile_size: (2, 2), map_size: (3, 2), cells: &[1, 2, 1, 0, 2, 3] },
+            TestLayer { tile_size: (4, 2), map_size: (2, 1), cells: &[0, 4] },
+        ];
+        assert_same_as_owned(&raw_map(&palette, &two_layers, &tiles));
+    }
+
+    #[test]
+    fn borrowed_and_owned_parsers_reject_the_same_data() {
+        let layer = [TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[1, 2] }];
+        let data = raw_map(&[0, 0x7fff], &layer, &[&[1; 4], &[1; 4]]);
+        let patched = |at: usize, value: u32| {
+            let mut data = data.clone();
+            data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            data
+        };
+
+        // The layer's magic is at 28, after the header and a palette of two colours
+        let corrupt = [
+            raw_map(&[0, 0x7fff], &[], &[]),
+            patched(12, u32::MAX),
+            patched(20, 0x4000_0000),
+            patched(32, 0),
+            patched(32, 1000),
+            patched(40, 0x10000),
+            patched(60, 0x0010_0000),
+            data[..data.len() - 2].to_vec(),
+        ];
+        for data in corrupt {
+            let owned = load(&data).err().expect("the owned parser accepted corrupt data");
+            let borrowed = parse_map_ref(&data).err().expect("the borrowed parser accepted corrupt data");
+            assert!(borrowed.downcast_ref::<MapError>().is_some(), "{} is not a MapError", borrowed);
+            assert_eq!(borrowed.to_string(), owned.to_string());
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::{BTreeMap, HashMap};
+
+use speedy2d::color::Color;
+use speedy2d::dimen::{UVec2, Vec2};
+use speedy2d::image::ImageHandle;
+use speedy2d::shape::Rectangle;
+use speedy2d::Graphics2D;
+
+use kknd2_mapview::map::{Map, Tile};
+
+const TILE_SCALE: f32 = 2.0;
+const CELL_PADDING: f32 = 8.0;
+
+// Grid of every unique tile in the map, with a selection cursor
+pub struct TileBrowser {
+    // Each tile id with the first layer that has it, whose image is shown
+    tiles: Vec<(u32, usize)>,
+    // How many cells of the base layer use each tile
+    usage: HashMap<u32, u32>,
+    selection: usize,
+    cell_size: f32,
+    scroll_row: usize,
+}
+
+impl TileBrowser {
+    pub fn new(map: &Map) -> TileBrowser {
+        let mut tiles: Vec<(u32, usize)> = map.iter_tiles().map(|(l, tile_id, _)| (tile_id, l)).collect();
+        tiles.sort();
+        tiles.dedup_by_key(|(tile_id, _)| *tile_id);
+
+        let tile_size = map.layers.iter().map(|layer| layer.tile_size.width.max(layer.tile_size.height)).max().unwrap_or(32);
+
+        TileBrowser {
+            tiles,
+            usage: map.layers.first().map(|layer| layer.tile_usage()).unwrap_or_default(),
+            selection: 0,
+            cell_size: tile_size as f32 * TILE_SCALE + CELL_PADDING,
+            scroll_row: 0,
+        }
+    }
+
+    pub fn selected_tile(&self) -> Option<u32> {
+        self.tiles.get(self.selection).map(|(tile_id, _)| *tile_id)
+    }
+
+    pub fn selected_usage(&self) -> u32 {
+        self.selected_tile().and_then(|tile_id| self.usage.get(&tile_id).copied()).unwrap_or(0)
+    }
+
+    fn columns(&self, window_size: UVec2) -> usize {
+        ((window_size.x as f32 / self.cell_size) as usize).max(1)
+    }
+
+    fn visible_rows(&self, window_size: UVec2) -> usize {
+        ((window_size.y as f32 / self.cell_size) as usize).max(1)
+    }
+
+    pub fn move_selection(&mut self, dx: isize, dy: isize, window_size: UVec2) {
+        if self.tiles.is_empty() {
+            return;
+        }
+
+        let columns = self.columns(window_size) as isize;
+        let selection = self.selection as isize + dx + dy * columns;
+        self.selection = selection.clamp(0, self.tiles.len() as isize - 1) as usize;
+
+        // Scroll so the selection stays on screen
+        let row = self.selection / columns as usize;
+        let visible_rows = self.visible_rows(window_size);
+        if row < self.scroll_row {
+            self.scroll_row = row;
+        } else if row >= self.scroll_row + visible_rows {
+            self.scroll_row = row + 1 - visible_rows;
+        }
+    }
+
+    // Returns the index of the tile under a screen position
+    pub fn hit_test(&self, position: Vec2, window_size: UVec2) -> Option<usize> {
+        if position.x < 0.0 || position.y < 0.0 {
+            return None;
+        }
+
+        let column = (position.x / self.cell_size) as usize;
+        let row = (position.y / self.cell_size) as usize + self.scroll_row;
+        let columns = self.columns(window_size);
+        if column >= columns {
+            return None;
+        }
+
+        let index = row * columns + column;
+        (index < self.tiles.len()).then_some(index)
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if index < self.tiles.len() {
+            self.selection = index;
+        }
+    }
+
+    pub fn draw(&self, graphics: &mut Graphics2D, window_size: UVec2, images: &HashMap<(usize, u32), ImageHandle>) {
+        graphics.clear_screen(Color::from_rgb(0.15, 0.15, 0.15));
+
+        let columns = self.columns(window_size);
+        let first = self.scroll_row * columns;
+        let last = (first + (self.visible_rows(window_size) + 1) * columns).min(self.tiles.len());
+
+        for index in first..last {
+            let cell = Vec2::new(
+                ((index % columns) as f32) * self.cell_size,
+                ((index / columns - self.scroll_row) as f32) * self.cell_size,
+            );
+
+            let (tile_id, layer) = self.tiles[index];
+            if let Some(image) = images.get(&(layer, tile_id)) {
+                let size = image.size().into_f32() * TILE_SCALE;
+                let top_left = cell + Vec2::new(CELL_PADDING, CELL_PADDING) / 2.0;
+                graphics.draw_rectangle_image(Rectangle::new(top_left, top_left + size), image);
+            }
+
+            if index == self.selection {
+                draw_outline(graphics, cell, cell + Vec2::new(self.cell_size, self.cell_size), Color::YELLOW);
+            }
+        }
+    }
+}
+
+pub fn draw_outline(graphics: &mut Graphics2D, top_left: Vec2, bottom_right: Vec2, colour: Color) {
+    let top_right = Vec2::new(bottom_right.x, top_left.y);
+    let bottom_left = Vec2::new(top_left.x, bottom_right.y);
+
+    graphics.draw_line(top_left, top_right, 2.0, colour);
+    graphics.draw_line(top_right, bottom_right, 2.0, colour);
+    graphics.draw_line(bottom_right, bottom_left, 2.0, colour);
+    graphics.draw_line(bottom_left, top_left, 2.0, colour);
+}
+
+// Summary of a tile for cataloguing, printed from the browser
+pub fn describe_tile(tile_id: u32, tile: &Tile) -> String {
+    let mut colours = BTreeMap::<[u8; 4], usize>::new();
+    for pixel in tile.pixels.chunks_exact(4) {
+        *colours.entry([pixel[0], pixel[1], pixel[2], pixel[3]]).or_default() += 1;
+    }
+
+    let mut description = format!("Tile {:#x}: {} colours", tile_id, colours.len());
+    for (colour, count) in colours {
+        if colour[3] == 0 {
+            description.push_str(&format!("\n  transparent x{}", count));
+        } else {
+            description.push_str(&format!("\n  #{:02x}{:02x}{:02x} x{}", colour[0], colour[1], colour[2], count));
+        }
+    }
+
+    let mut indices = BTreeMap::<u8, usize>::new();
+    for index in &tile.indices {
+        *indices.entry(*index).or_default() += 1;
+    }
+
+    description.push_str(&format!("\n{} palette indices", indices.len()));
+    for (index, count) in indices {
+        description.push_str(&format!("\n  index {} x{}", index, count));
+    }
+    description
+}
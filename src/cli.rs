@@ -0,0 +1,266 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+#[derive(Default)]
+pub enum Command {
+    #[default]
+    View,
+    Thumbnails { input_dir: PathBuf, output_dir: PathBuf },
+    Dimensions(PathBuf),
+    ValidateDirectory(PathBuf),
+    Histogram(PathBuf),
+    DiffPalette(PathBuf, PathBuf),
+    Version,
+    ExportPalette { map: PathBuf, output: PathBuf },
+}
+
+#[derive(Default)]
+pub struct Options {
+    pub command: Command,
+    // Map given as a bare path, which is how file managers open associated files
+    pub open: Option<PathBuf>,
+    pub palette: Option<PathBuf>,
+    // Reopen the most recently loaded map on startup
+    pub continue_last: bool,
+    // Show y coordinates counted up from the bottom of the map
+    pub origin_bottom_left: bool,
+    // 0 by default, each -v or --verbose raises the log level one step
+    pub verbosity: u8,
+    // Read the top bit of palette entries as transparency
+    pub palette_alpha: bool,
+    // Load tiles cut off by the end of the file as placeholders
+    pub allow_truncated: bool,
+    // Fraction of a layer's cells sharing one tile id that triggers a misparse warning
+    pub uniform_threshold: Option<f32>,
+    // Pixels the view moves per frame while an arrow key is held
+    pub pan_speed: Option<u32>,
+    // Zoom factor for one notch of the mouse wheel
+    pub zoom_step: Option<f32>,
+    // TrueType font used instead of the embedded one
+    pub font: Option<PathBuf>,
+    // Draw placeholders instead of creating tile images, for profiling
+    pub placeholder_tiles: bool,
+    // Print a line to stdout whenever a map finishes loading, for scripts driving the viewer
+    pub print_on_load: bool,
+    // Merge tiles that are stored more than once, saving memory and textures
+    pub dedup_tiles: bool,
+    // Tile images a map can need before duplicate tiles are merged and a warning shown
+    pub max_textures: Option<usize>,
+    // Indices of the layers to load, counted from 0
+    pub layers: Option<Vec<usize>>,
+    // Width and height in pixels that tiles are read as, whatever the layer headers say
+    pub tile_size: Option<(u32, u32)>,
+    // Skip reading tiles and draw each one as a colour picked from its id
+    pub layout_only: bool,
+    // Brush edits kept for undo
+    pub undo_depth: Option<usize>,
+    // Show the grid on startup with lines every this many tiles
+    pub grid_spacing: Option<u32>,
+    // Luminance below which decoded colours are drawn partly transparent
+    pub dark_threshold: Option<u8>,
+    // Colour rendered behind thumbnails instead of leaving empty areas transparent
+    pub background_fill: Option<(u8, u8, u8)>,
+    // A zip file and the name of the map inside it to open on startup
+    pub zip: Option<(PathBuf, String)>,
+    // View state JSON printed by the viewer, restored once the first map loads
+    pub view: Option<String>,
+    // A map to open on startup and reload whenever it changes on disk
+    pub watch: Option<PathBuf>,
+}
+
+// Parses RRGGBB, with or without a leading #
+fn parse_colour(value: &OsString) -> Result<(u8, u8, u8), Box<dyn Error>> {
+    let invalid = || format!("Invalid colour {:?}, expected RRGGBB", value);
+    let hex = value.to_str().ok_or_else(invalid)?;
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid().into());
+    }
+
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok((component(0)?, component(2)?, component(4)?))
+}
+
+// Paths aren't necessarily UTF-8, so values are kept as OS strings
+fn next_value(args: &mut impl Iterator<Item = OsString>, flag: &str) -> Result<OsString, Box<dyn Error>> {
+    args.next().ok_or_else(|| format!("Missing value for {}", flag).into())
+}
+
+pub fn parse_args() -> Result<Options, Box<dyn Error>> {
+    let mut options = Options::default();
+    let mut args = env::args_os().skip(1);
+
+    let mut thumbnails: Option<PathBuf> = None;
+    let mut export_palette: Option<PathBuf> = None;
+    let mut out: Option<PathBuf> = None;
+    let mut zip: Option<PathBuf> = None;
+    let mut entry: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        if !arg.to_string_lossy().starts_with('-') {
+            if options.open.replace(PathBuf::from(arg)).is_some() {
+                return Err("Only one map can be opened at a time".into());
+            }
+            continue;
+        }
+
+        let arg = arg.into_string().map_err(|arg| format!("Unknown argument: {:?}", arg))?;
+        match arg.as_str() {
+            "--palette" => options.palette = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--continue" => options.continue_last = true,
+            "--verbose" | "-v" => options.verbosity = options.verbosity.saturating_add(1),
+            "-vv" => options.verbosity = options.verbosity.saturating_add(2),
+            "--palette-alpha" => options.palette_alpha = true,
+            "--allow-truncated" => options.allow_truncated = true,
+            "--thumbnails" => thumbnails = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--export-palette" => export_palette = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--dimensions" => options.command = Command::Dimensions(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--validate-dir" => {
+                options.command = Command::ValidateDirectory(PathBuf::from(next_value(&mut args, &arg)?));
+            }
+            "--diff-palette" => {
+                let a = PathBuf::from(next_value(&mut args, &arg)?);
+                let b = PathBuf::from(next_value(&mut args, &arg)?);
+                options.command = Command::DiffPalette(a, b);
+            }
+            "--version" | "--format-info" => options.command = Command::Version,
+            "--histogram" => options.command = Command::Histogram(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--zip" => zip = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--entry" => {
+                let value = next_value(&mut args, &arg)?;
+                entry = Some(value.into_string().map_err(|value| format!("Invalid zip entry name: {:?}", value))?);
+            }
+            "--view" => {
+                let value = next_value(&mut args, &arg)?;
+                options.view = Some(value.into_string().map_err(|value| format!("Invalid view state: {:?}", value))?);
+            }
+            "--origin" => match next_value(&mut args, &arg)?.to_str() {
+                Some("top-left") => options.origin_bottom_left = false,
+                Some("bottom-left") => options.origin_bottom_left = true,
+                _ => return Err("--origin must be top-left or bottom-left".into()),
+            },
+            "--watch" => options.watch = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--background-fill" => options.background_fill = Some(parse_colour(&next_value(&mut args, &arg)?)?),
+            "--dark-threshold" => {
+                let value = next_value(&mut args, &arg)?;
+                let threshold = value
+                    .to_str()
+                    .and_then(|value| value.parse::<u8>().ok())
+                    .ok_or_else(|| format!("Invalid dark threshold {:?}, expected a luminance from 0 to 255", value))?;
+                options.dark_threshold = Some(threshold);
+            }
+            "--grid" => {
+                let value = next_value(&mut args, &arg)?;
+                let spacing = value
+                    .to_str()
+                    .and_then(|value| value.parse::<u32>().ok())
+                    .filter(|spacing| *spacing > 0)
+                    .ok_or_else(|| format!("Invalid grid spacing {:?}, expected a positive number of tiles", value))?;
+                options.grid_spacing = Some(spacing);
+            }
+            // Numbered from 1 as in the status bar, e.g. --layers 1,3
+            "--layers" => {
+                let value = next_value(&mut args, &arg)?;
+                let parse_layer = |layer: &str| layer.trim().parse::<usize>().ok().filter(|layer| *layer > 0);
+                let layers = value
+                    .to_str()
+                    .and_then(|value| value.split(',').map(|layer| parse_layer(layer).map(|layer| layer - 1)).collect())
+                    .ok_or_else(|| format!("Invalid layers {:?}, expected layer numbers such as 1,3", value))?;
+                options.layers = Some(layers);
+            }
+            "--tile-size" => {
+                let value = next_value(&mut args, &arg)?;
+                let size = value
+                    .to_str()
+                    .and_then(|value| value.split_once(['x', 'X']))
+                    .and_then(|(width, height)| Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?)))
+                    .filter(|(width, height)| *width > 0 && *height > 0)
+                    .ok_or_else(|| format!("Invalid tile size {:?}, expected WIDTHxHEIGHT such as 32x32", value))?;
+                options.tile_size = Some(size);
+            }
+            "--undo-depth" => {
+                let value = next_value(&mut args, &arg)?;
+                let depth = value
+                    .to_str()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .ok_or_else(|| format!("Invalid undo depth {:?}, expected a number of edits", value))?;
+                options.undo_depth = Some(depth);
+            }
+            "--uniform-threshold" => {
+                let value = next_value(&mut args, &arg)?;
+                let percent = value
+                    .to_str()
+                    .and_then(|value| value.parse::<f32>().ok())
+                    .filter(|percent| (0.0..=100.0).contains(percent))
+                    .ok_or_else(|| format!("Invalid uniform threshold {:?}, expected a percentage", value))?;
+                options.uniform_threshold = Some(percent / 100.0);
+            }
+            "--placeholder-tiles" => options.placeholder_tiles = true,
+            "--layout-only" => options.layout_only = true,
+            "--dedup-tiles" => options.dedup_tiles = true,
+            "--max-textures" => {
+                let value = next_value(&mut args, &arg)?;
+                let count = value
+                    .to_str()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .ok_or_else(|| format!("Invalid texture limit {:?}, expected a number of images", value))?;
+                options.max_textures = Some(count);
+            }
+            "--print-on-load" => options.print_on_load = true,
+            "--font" => options.font = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--pan-speed" => {
+                let value = next_value(&mut args, &arg)?;
+                let speed = value
+                    .to_str()
+                    .and_then(|value| value.parse::<u32>().ok())
+                    .filter(|speed| *speed > 0)
+                    .ok_or_else(|| format!("Invalid pan speed {:?}, expected a positive number of pixels", value))?;
+                options.pan_speed = Some(speed);
+            }
+            "--zoom-step" => {
+                let value = next_value(&mut args, &arg)?;
+                let step = value
+                    .to_str()
+                    .and_then(|value| value.parse::<f32>().ok())
+                    .filter(|step| step.is_finite() && *step > 1.0)
+                    .ok_or_else(|| format!("Invalid zoom step {:?}, expected a factor above 1 such as 1.25", value))?;
+                options.zoom_step = Some(step);
+            }
+            "--out" => out = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            _ => return Err(format!("Unknown argument: {}", arg).into()),
+        }
+    }
+
+    if let Some(input_dir) = thumbnails {
+        let output_dir = out.ok_or("--thumbnails requires --out <dir>")?;
+        options.command = Command::Thumbnails { input_dir, output_dir };
+    } else if let Some(map) = export_palette {
+        let output = out.ok_or("--export-palette requires --out <file>")?;
+        options.command = Command::ExportPalette { map, output };
+    } else if out.is_some() {
+        return Err("--out is only valid with --thumbnails or --export-palette".into());
+    }
+
+    if options.background_fill.is_some() && !matches!(options.command, Command::Thumbnails { .. }) {
+        return Err("--background-fill is only valid with --thumbnails".into());
+    }
+
+    if options.open.is_some() && !matches!(options.command, Command::View) {
+        return Err("A map to open can't be combined with a batch command".into());
+    }
+
+    match (zip, entry) {
+        (Some(zip), Some(entry)) => options.zip = Some((zip, entry)),
+        (None, None) => {}
+        _ => return Err("--zip and --entry must be used together".into()),
+    }
+
+    Ok(options)
+}
@@ -4,32 +4,40 @@
 // SPDX-License-Identifier: MIT
 
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 
 fn read_u16(buffer: &[u8], offset: usize) -> Result<u16, Box<dyn Error>> {
-    Ok(u16::from_le_bytes(buffer[offset..offset + 2].try_into()?))
+    let bytes = buffer.get(offset..offset + 2).ok_or("Compressed data ends in the middle of a code word")?;
+    Ok(u16::from_le_bytes(bytes.try_into()?))
 }
 
+// A back-reference is two bytes of input for at most 16 bytes of output
+const MAX_EXPANSION: usize = 8;
+
 fn decompress_data(input: &[u8], output_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
     let mut input_cursor: usize = 0;
     let mut output_cursor: usize = 0;
     let mut counter: u32 = 0;
     let mut code_bits = 0;
+    if output_size > input.len().saturating_mul(MAX_EXPANSION) {
+        return Err(format!("{} compressed bytes can't expand to {} bytes", input.len(), output_size).into());
+    }
     let mut output: Vec<u8> = vec![0; output_size];
 
     while input_cursor < input.len() {
         if counter == 0 {
             let word = read_u16(input, input_cursor)?;
 
-            code_bits = code_bits & !0xffff | word;
+            code_bits = word;
             input_cursor += 2;
             counter = 16;
         }
 
         if (code_bits & 1) == 1 {
+            if input_cursor + 1 >= input.len() {
+                return Err("Compressed data ends in the middle of a back-reference".into());
+            }
             let source_copy_cursor =
                 ((((input[input_cursor] as u16) << 4) & !0xff) | (input[input_cursor + 1] as u16)) as usize;
 
@@ -37,12 +45,14 @@ fn decompress_data(input: &[u8], output_size: usize) -> Result<Vec<u8>, Box<dyn
             if source_copy_cursor > output_cursor {
                 return Err("source_copy_cursor > output_cursor".into());
             }
-            let mut copy_cursor = output_cursor - source_copy_cursor;
+            if output_cursor + pattern_size as usize > output.len() {
+                return Err("Back-reference runs past the end of the output".into());
+            }
+            let copy_cursor = output_cursor - source_copy_cursor;
 
-            for _ in 0..pattern_size {
-                output[output_cursor] = output[copy_cursor];
+            for i in 0..pattern_size as usize {
+                output[output_cursor] = output[copy_cursor + i];
                 output_cursor += 1;
-                copy_cursor += 1;
             }
 
             input_cursor += 2;
@@ -63,22 +73,19 @@ fn decompress_data(input: &[u8], output_size: usize) -> Result<Vec<u8>, Box<dyn
     Ok(output)
 }
 
-fn decompress_block(output_size: usize, input: &Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+fn decompress_block(output_size: usize, input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     if output_size == input.len() {
-        return Ok(input.clone());
+        return Ok(input.to_vec());
     }
 
     decompress_data(input, output_size)
 }
 
-fn decompress_part<R: Read>(
+fn decompress_part<R: Read + Seek>(
     reader: &mut BufReader<R>,
     big_endian: bool,
-) -> Result<Vec<u8>, Box<dyn Error>>
-where
-    R: Seek,
-{
-    let mut decompressed_bytes: u32 = 0;
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut decompressed_bytes: u64 = 0;
 
     let part_uncompressed_size = if big_endian {
         reader.read_u32::<BigEndian>()?
@@ -90,16 +97,24 @@ where
 
     let mut output: Vec<u8> = vec![];
 
-    while decompressed_bytes < part_uncompressed_size {
+    while decompressed_bytes < part_uncompressed_size as u64 {
         let chunk_uncompressed_size = reader.read_u32::<LittleEndian>()?;
         let chunk_compressed_size = reader.read_u32::<LittleEndian>()?;
+        // An empty chunk would never finish the part
+        if chunk_uncompressed_size == 0 {
+            return Err("Empty chunk in compressed data".into());
+        }
 
-        let mut chunk_buffer: Vec<u8> = vec![0; chunk_compressed_size as usize];
-        reader.read_exact(&mut chunk_buffer)?;
+        // Read through take so a corrupt size can't allocate more than the file holds
+        let mut chunk_buffer: Vec<u8> = vec![];
+        reader.by_ref().take(chunk_compressed_size as u64).read_to_end(&mut chunk_buffer)?;
+        if chunk_buffer.len() != chunk_compressed_size as usize {
+            return Err("Compressed data ends in the middle of a chunk".into());
+        }
 
         let decompressed_chunk = decompress_block(chunk_uncompressed_size as usize, &chunk_buffer)?;
 
-        decompressed_bytes += output.write(decompressed_chunk.as_slice())? as u32;
+        decompressed_bytes += output.write(decompressed_chunk.as_slice())? as u64;
     }
 
     Ok(output)
@@ -110,16 +125,39 @@ pub struct DecompressedFile {
     pub _metadata: Vec<u8>,
 }
 
-pub fn decompress(path: &PathBuf) -> Result<DecompressedFile, Box<dyn Error>> {
-    let file = File::open(&path).map_err(|e| format!("Failed to open file: {}", e))?;
-
-    let mut reader = BufReader::new(file);
+pub fn decompress<R: Read + Seek>(reader: &mut BufReader<R>) -> Result<DecompressedFile, Box<dyn Error>> {
+    reader.seek(SeekFrom::Start(0))?;
 
     let _magic = reader.read_u32::<LittleEndian>()?;
     reader.seek_relative(4)?;
 
-    let archive = decompress_part(&mut reader, true)?;
-    let _metadata = decompress_part(&mut reader, false)?;
+    let archive = decompress_part(reader, true)?;
+    let _metadata = decompress_part(reader, false)?;
 
     Ok(DecompressedFile{ archive, _metadata })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Code word 0b10: a literal 'a', then a back-reference 1 byte back for 4 bytes
+    const RUN_OF_A: [u8; 5] = [0b10, 0, b'a', 0x03, 0x01];
+
+    #[test]
+    fn back_reference_repeats_output() {
+        assert_eq!(decompress_data(&RUN_OF_A, 5).unwrap(), b"aaaaa");
+    }
+
+    #[test]
+    fn corrupt_data_is_an_error() {
+        // Back-reference cut off after its first byte
+        assert!(decompress_data(&RUN_OF_A[..4], 5).is_err());
+        // Back-reference that runs past the end of the output
+        assert!(decompress_data(&RUN_OF_A, 3).is_err());
+        // Code word cut off after its first byte
+        assert!(decompress_data(&[0], 1).is_err());
+        // More output than the input could ever expand to
+        assert!(decompress_data(&RUN_OF_A, 1 << 30).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use image::RgbaImage;
+use log::warn;
+use speedy2d::color::Color;
+use speedy2d::dimen::{UVec2, Vec2};
+use speedy2d::image::{ImageDataType, ImageHandle, ImageSmoothingMode};
+use speedy2d::shape::Rectangle;
+use speedy2d::window::UserEventSender;
+use speedy2d::Graphics2D;
+
+use kknd2_mapview::map::{load_map, LoadOptions};
+use kknd2_mapview::render::{render_thumbnail, RenderOptions};
+
+use crate::batch::list_maps;
+use crate::browser::draw_outline;
+use crate::viewer::MapViewEvent;
+
+const THUMBNAIL_WIDTH: u32 = 128;
+// Square the thumbnail is fitted into, with the file name below it
+const THUMBNAIL_BOX: f32 = 128.0;
+const LABEL_HEIGHT: f32 = 24.0;
+const CELL_WIDTH: f32 = THUMBNAIL_BOX + 32.0;
+const CELL_HEIGHT: f32 = THUMBNAIL_BOX + LABEL_HEIGHT + 16.0;
+// Room above the grid for the directory name
+const HEADER_HEIGHT: f32 = 40.0;
+const MARGIN: f32 = 16.0;
+
+enum Thumbnail {
+    // Requested from the worker thread
+    Pending,
+    // Rendered, waiting for the next frame to upload it
+    Rendered(RgbaImage),
+    Ready(ImageHandle),
+    // The map couldn't be loaded or rendered, only its name is shown
+    Failed,
+}
+
+// Renders thumbnails off the UI thread, in the order they are requested. The thread
+// exits once the sender is dropped along with the browser
+fn spawn_thumbnail_worker(event_sender: UserEventSender<MapViewEvent>) -> mpsc::Sender<PathBuf> {
+    let (requests, pending) = mpsc::channel::<PathBuf>();
+
+    thread::spawn(move || {
+        for path in pending {
+            let thumbnail = load_map(&path, &LoadOptions::default())
+                .and_then(|map| render_thumbnail(&map, THUMBNAIL_WIDTH, &RenderOptions::default()))
+                .map_err(|e| e.to_string());
+
+            if event_sender.send_event(MapViewEvent::ThumbnailRendered(path, thumbnail)).is_err() {
+                break;
+            }
+        }
+    });
+
+    requests
+}
+
+// Grid of the map files in a directory with a thumbnail of each, and a selection cursor.
+// Thumbnails are only rendered once their cell scrolls into view
+pub struct DirectoryBrowser {
+    directory: PathBuf,
+    entries: Vec<PathBuf>,
+    selection: usize,
+    scroll_row: usize,
+    thumbnails: HashMap<PathBuf, Thumbnail>,
+    requests: mpsc::Sender<PathBuf>,
+}
+
+impl DirectoryBrowser {
+    pub fn open(
+        directory: &Path,
+        event_sender: UserEventSender<MapViewEvent>,
+    ) -> Result<DirectoryBrowser, Box<dyn Error>> {
+        Ok(DirectoryBrowser {
+            directory: directory.to_path_buf(),
+            entries: list_maps(directory)?,
+            selection: 0,
+            scroll_row: 0,
+            thumbnails: HashMap::new(),
+            requests: spawn_thumbnail_worker(event_sender),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn selected(&self) -> Option<&Path> {
+        self.entries.get(self.selection).map(PathBuf::as_path)
+    }
+
+    fn columns(&self, window_size: UVec2, scale_factor: f32) -> usize {
+        let width = window_size.x as f32 - 2.0 * MARGIN * scale_factor;
+        ((width / (CELL_WIDTH * scale_factor)) as usize).max(1)
+    }
+
+    fn visible_rows(&self, window_size: UVec2, scale_factor: f32) -> usize {
+        let height = window_size.y as f32 - HEADER_HEIGHT * scale_factor;
+        ((height / (CELL_HEIGHT * scale_factor)) as usize).max(1)
+    }
+
+    pub fn move_selection(&mut self, dx: isize, dy: isize, window_size: UVec2, scale_factor: f32) {
+        if self.entries.is_empty() {
+            return;
+        }
+
+        let columns = self.columns(window_size, scale_factor);
+        let selection = self.selection as isize + dx + dy * columns as isize;
+        self.selection = selection.clamp(0, self.entries.len() as isize - 1) as usize;
+
+        // Scroll so the selection stays on screen
+        let row = self.selection / columns;
+        let visible_rows = self.visible_rows(window_size, scale_factor);
+        if row < self.scroll_row {
+            self.scroll_row = row;
+        } else if row >= self.scroll_row + visible_rows {
+            self.scroll_row = row + 1 - visible_rows;
+        }
+    }
+
+    // Returns the index of the entry under a screen position
+    pub fn hit_test(&self, position: Vec2, window_size: UVec2, scale_factor: f32) -> Option<usize> {
+        let relative = position - Vec2::new(MARGIN, HEADER_HEIGHT) * scale_factor;
+        if relative.x < 0.0 || relative.y < 0.0 {
+            return None;
+        }
+
+        let column = (relative.x / (CELL_WIDTH * scale_factor)) as usize;
+        let row = (relative.y / (CELL_HEIGHT * scale_factor)) as usize + self.scroll_row;
+        let columns = self.columns(window_size, scale_factor);
+        if column >= columns {
+            return None;
+        }
+
+        let index = row * columns + column;
+        (index < self.entries.len()).then_some(index)
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if index < self.entries.len() {
+            self.selection = index;
+        }
+    }
+
+    // Indices of the entries in view and the top left corner of each one's cell
+    fn visible_cells(&self, window_size: UVec2, scale_factor: f32) -> Vec<(usize, Vec2)> {
+        let columns = self.columns(window_size, scale_factor);
+        let first = self.scroll_row * columns;
+        // One extra row for the partly visible one at the bottom
+        let last = (first + (self.visible_rows(window_size, scale_factor) + 1) * columns).min(self.entries.len());
+
+        (first..last)
+            .map(|index| {
+                let cell = Vec2::new(
+                    MARGIN + (index % columns) as f32 * CELL_WIDTH,
+                    HEADER_HEIGHT + (index / columns - self.scroll_row) as f32 * CELL_HEIGHT,
+                ) * scale_factor;
+                (index, cell)
+            })
+            .collect()
+    }
+
+    // Queues a thumbnail for every cell in view that doesn't have one yet
+    pub fn request_visible(&mut self, window_size: UVec2, scale_factor: f32) {
+        for (index, _) in self.visible_cells(window_size, scale_factor) {
+            let path = &self.entries[index];
+            if !self.thumbnails.contains_key(path) {
+                self.thumbnails.insert(path.clone(), Thumbnail::Pending);
+                // The worker only stops early if the window has closed
+                let _ = self.requests.send(path.clone());
+            }
+        }
+    }
+
+    // Results for another directory, e.g. one open before this browser, are ignored
+    pub fn thumbnail_rendered(&mut self, path: PathBuf, thumbnail: Result<RgbaImage, String>) {
+        let entry = match self.thumbnails.get_mut(&path) {
+            Some(entry @ Thumbnail::Pending) => entry,
+            _ => return,
+        };
+
+        *entry = match thumbnail {
+            Ok(image) => Thumbnail::Rendered(image),
+            Err(e) => {
+                warn!("No thumbnail for {}: {}", path.display(), e);
+                Thumbnail::Failed
+            }
+        };
+    }
+
+    // Images can only be created on the UI thread, while drawing
+    fn upload_thumbnails(&mut self, graphics: &mut Graphics2D) {
+        for (path, thumbnail) in self.thumbnails.iter_mut() {
+            let image = match thumbnail {
+                Thumbnail::Rendered(image) => image,
+                _ => continue,
+            };
+
+            let handle = graphics.create_image_from_raw_pixels(
+                ImageDataType::RGBA,
+                ImageSmoothingMode::Linear,
+                (image.width(), image.height()),
+                image.as_raw(),
+            );
+            *thumbnail = match handle {
+                Ok(handle) => Thumbnail::Ready(handle),
+                Err(e) => {
+                    warn!("Failed to create thumbnail image for {}: {}", path.display(), e);
+                    Thumbnail::Failed
+                }
+            };
+        }
+    }
+
+    // Draws the thumbnails and the selection outline, and returns each visible file
+    // name with the position to draw it at, as the text is laid out by the viewer
+    pub fn draw(&mut self, graphics: &mut Graphics2D, window_size: UVec2, scale_factor: f32) -> Vec<(String, Vec2)> {
+        graphics.clear_screen(Color::from_rgb(0.15, 0.15, 0.15));
+        self.upload_thumbnails(graphics);
+
+        let box_size = THUMBNAIL_BOX * scale_factor;
+        let mut labels = Vec::new();
+        for (index, cell) in self.visible_cells(window_size, scale_factor) {
+            let path = &self.entries[index];
+            let image_top_left = cell + Vec2::new((CELL_WIDTH - THUMBNAIL_BOX) / 2.0, 8.0) * scale_factor;
+
+            if let Some(Thumbnail::Ready(image)) = self.thumbnails.get(path) {
+                // Fit inside the square, keeping the map's aspect ratio
+                let size = image.size().into_f32();
+                let scale = (box_size / size.x).min(box_size / size.y);
+                let fitted = size * scale;
+                let top_left = image_top_left + (Vec2::new(box_size, box_size) - fitted) / 2.0;
+                graphics.draw_rectangle_image(Rectangle::new(top_left, top_left + fitted), image);
+            }
+
+            if index == self.selection {
+                let size = Vec2::new(CELL_WIDTH, CELL_HEIGHT) * scale_factor;
+                draw_outline(graphics, cell, cell + size, Color::YELLOW);
+            }
+
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            labels.push((name, image_top_left + Vec2::new(0.0, box_size + 4.0 * scale_factor)));
+        }
+        labels
+    }
+
+    // Position the directory name is drawn at
+    pub fn header_position(&self, scale_factor: f32) -> Vec2 {
+        Vec2::new(MARGIN, MARGIN / 2.0) * scale_factor
+    }
+}
@@ -0,0 +1,74 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::VecDeque;
+
+pub const DEFAULT_UNDO_DEPTH: usize = 100;
+
+// One cell changed by the brush, in tiles
+#[derive(Clone, Copy)]
+pub struct TileChange {
+    pub layer: usize,
+    pub x: u32,
+    pub y: u32,
+    pub old: u32,
+    pub new: u32,
+}
+
+// Undo and redo stacks of edits, each one the cells changed by a single click or drag.
+// Only the changed cells are kept rather than copies of the map, and the oldest edit
+// is dropped once there are more than depth
+pub struct EditHistory {
+    undo: VecDeque<Vec<TileChange>>,
+    redo: Vec<Vec<TileChange>>,
+    // Changes of the drag in progress, moved onto the undo stack when it ends
+    stroke: Vec<TileChange>,
+    depth: usize,
+}
+
+impl EditHistory {
+    pub fn new(depth: usize) -> EditHistory {
+        EditHistory { undo: VecDeque::new(), redo: Vec::new(), stroke: Vec::new(), depth }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.stroke.clear();
+    }
+
+    // A new edit makes the undone ones unreachable, so they are dropped
+    pub fn record(&mut self, change: TileChange) {
+        self.redo.clear();
+        self.stroke.push(change);
+    }
+
+    pub fn end_stroke(&mut self) {
+        if self.stroke.is_empty() {
+            return;
+        }
+
+        self.undo.push_back(std::mem::take(&mut self.stroke));
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+
+    // Returns the changes of the last edit, to be put back in reverse order
+    pub fn undo(&mut self) -> Option<&[TileChange]> {
+        self.end_stroke();
+        let edit = self.undo.pop_back()?;
+        self.redo.push(edit);
+        self.redo.last().map(Vec::as_slice)
+    }
+
+    // Returns the changes of the last undone edit, to be applied again in order
+    pub fn redo(&mut self) -> Option<&[TileChange]> {
+        self.end_stroke();
+        let edit = self.redo.pop()?;
+        self.undo.push_back(edit);
+        self.undo.back().map(Vec::as_slice)
+    }
+}
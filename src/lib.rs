@@ -0,0 +1,15 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+pub mod borrowed;
+pub mod decompress;
+pub mod map;
+pub mod palette;
+pub mod render;
+pub mod source;
+#[doc(hidden)]
+pub mod test_support;
+pub mod unpack;
+pub mod viewport;
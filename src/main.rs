@@ -4,29 +4,151 @@
 // SPDX-License-Identifier: MIT
 
 use std::error::Error;
+use std::process;
+#[cfg(feature = "gui")]
+use std::{fs, path::Path};
 
-use speedy2d::dimen::UVec2;
-use speedy2d::font::Font;
-use speedy2d::window::{WindowCreationOptions, WindowPosition, WindowSize};
-use speedy2d::Window;
+use log::LevelFilter;
+#[cfg(feature = "gui")]
+use log::warn;
+#[cfg(feature = "gui")]
+use speedy2d::{
+    dimen::UVec2,
+    font::Font,
+    window::{WindowCreationOptions, WindowPosition, WindowSize},
+    Window,
+};
 
-use crate::viewer::{MapView, MapViewEvent};
+use kknd2_mapview::render::RenderOptions;
 
-mod map;
+use crate::cli::{Command, Options};
+#[cfg(feature = "gui")]
+use crate::view_state::ViewState;
+#[cfg(feature = "gui")]
+use crate::viewer::{MapSource, MapView, MapViewEvent};
+
+mod batch;
+mod cli;
+#[cfg(feature = "gui")]
+mod browser;
+#[cfg(feature = "gui")]
+mod directory;
+#[cfg(feature = "gui")]
+mod history;
+#[cfg(feature = "gui")]
+mod palette_editor;
+#[cfg(feature = "gui")]
+mod recent;
+#[cfg(feature = "gui")]
+mod view_state;
+#[cfg(feature = "gui")]
 mod viewer;
-mod decompress;
-mod unpack;
+#[cfg(feature = "gui")]
+mod watch;
+
+#[cfg(feature = "gui")]
+fn load_embedded_font() -> Result<Font, Box<dyn Error>> {
+    let bytes = include_bytes!("../assets/NotoSans-Regular.ttf");
+    Font::new(bytes).map_err(|e| format!("Failed to load the embedded font: {}", e).into())
+}
+
+#[cfg(feature = "gui")]
+fn load_font_file(path: &Path) -> Result<Font, Box<dyn Error>> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to open font {:?}: {}", path, e))?;
+    Font::new(&bytes).map_err(|e| format!("Failed to load font {:?}: {}", path, e).into())
+}
+
+// A user supplied font falls back to the embedded one if it can't be loaded
+#[cfg(feature = "gui")]
+fn load_font(path: Option<&Path>) -> Result<Font, Box<dyn Error>> {
+    if let Some(path) = path {
+        match load_font_file(path) {
+            Ok(font) => return Ok(font),
+            Err(e) => warn!("{}, using the embedded font", e),
+        }
+    }
+
+    load_embedded_font()
+}
+
+// Quiet by default, RUST_LOG overrides the level picked by -v
+fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let options = cli::parse_args()?;
+    init_logging(options.verbosity);
+
+    match &options.command {
+        Command::View => {}
+        Command::Thumbnails { input_dir, output_dir } => {
+            let render_options = RenderOptions { background: options.background_fill };
+            return batch::generate_thumbnails(input_dir, output_dir, batch::THUMBNAIL_WIDTH, &render_options);
+        }
+        Command::ExportPalette { map, output } => return batch::export_palette(map, output),
+        Command::Histogram(path) => return batch::print_histogram(path),
+        Command::DiffPalette(a, b) => return batch::diff_palettes(a, b),
+        Command::Version => {
+            batch::print_version();
+            return Ok(());
+        }
+        Command::ValidateDirectory(path) => {
+            if !batch::validate_directory(path)? {
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        Command::Dimensions(path) => {
+            // Keep the error on a single readable line for shell scripts
+            if let Err(e) = batch::print_dimensions(path) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return Ok(());
+        }
+    }
+
+    run_viewer(options)
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_viewer(_options: Options) -> Result<(), Box<dyn Error>> {
+    Err("This build has no viewer, it was built without the gui feature. Use a batch command such as --thumbnails".into())
+}
+
+#[cfg(feature = "gui")]
+fn run_viewer(options: Options) -> Result<(), Box<dyn Error>> {
     // Enforce x11 mode for now
     std::env::set_var("WINIT_UNIX_BACKEND", "x11");
 
-    // Load the font
-    let bytes = include_bytes!("../assets/NotoSans-Regular.ttf");
-    let font = Font::new(bytes).unwrap();
+    // Maps can still be viewed without a font, just without any text
+    let font = match load_font(options.font.as_deref()) {
+        Ok(font) => Some(font),
+        Err(e) => {
+            warn!("{}, text will not be shown", e);
+            None
+        }
+    };
+
+    // Checked before the window opens, a typo shouldn't cost the view it was meant to restore
+    let view = options.view.as_deref().map(ViewState::parse).transpose().map_err(|e| format!("--view: {}", e))?;
+    let map_given = options.continue_last || options.open.is_some() || options.zip.is_some() || options.watch.is_some();
 
     let window = Window::<MapViewEvent>::new_with_user_events(
-        "KKnD 2 Map Viewer",
+        viewer::WINDOW_TITLE,
         WindowCreationOptions::new_windowed(
             WindowSize::PhysicalPixels(UVec2::from((1024, 768))),
             Option::from(WindowPosition::Center),
@@ -35,7 +157,64 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let event_sender = window.create_user_event_sender();
 
-    let map_view = MapView::new(font, event_sender);
+    let mut map_view = MapView::new(font, event_sender.clone(), &options);
+
+    if options.print_on_load {
+        map_view.set_on_load(|info| {
+            println!(
+                "Loaded {}: {}x{} tiles, {}x{} pixels, {} layers",
+                info.name,
+                info.map_size.width,
+                info.map_size.height,
+                info.pixel_size.width,
+                info.pixel_size.height,
+                info.layers
+            );
+        });
+    }
+
+    // The state also names its map, which is opened unless another one was given
+    if let Some(view) = view {
+        let source = match (&view.file, &view.zip) {
+            (_, Some((archive, entry))) => Some(MapSource::Zip { archive: archive.clone(), entry: entry.clone() }),
+            (Some(path), None) => Some(MapSource::File(path.clone())),
+            (None, None) => None,
+        };
+        map_view.restore_view(view);
+        if let (Some(source), false) = (source, map_given) {
+            map_view.open_map(source);
+        }
+    }
+
+    if options.continue_last {
+        match recent::recent_files().into_iter().next() {
+            Some(path) if path.is_file() => map_view.open_map(MapSource::File(path)),
+            Some(path) => warn!("Last map {} no longer exists", path.display()),
+            None => warn!("No recently opened maps to continue from"),
+        }
+    }
+
+    // A directory lists its maps to pick from instead
+    if let Some(path) = options.open {
+        if path.is_dir() {
+            map_view.open_directory(&path);
+        } else {
+            map_view.open_map(MapSource::File(path));
+        }
+    }
+
+    if let Some((archive, entry)) = options.zip {
+        map_view.open_map(MapSource::Zip { archive, entry });
+    }
+
+    // Keep the watcher alive for as long as the window is open
+    let _watcher = match options.watch {
+        Some(path) => {
+            map_view.open_map(MapSource::File(path.clone()));
+            Some(watch::watch_map(&path, event_sender)?)
+        }
+        None => None,
+    };
 
     window.run_loop(map_view)
 }
@@ -3,217 +3,1493 @@
 //
 // SPDX-License-Identifier: MIT
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, warn};
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use crate::decompress::decompress;
+use crate::palette::Colour;
+use crate::source::{load_map_from, ZipEntry};
 use crate::unpack;
-use crate::unpack::{FileEntry, unpack};
+use crate::unpack::{fourcc_to_string, FileEntry, unpack};
+use crate::viewport::Dimensions;
+use rayon::prelude::*;
 
-const DATA_HEADER_SIZE: u32 = 8;
+// Layer offsets and tile ids are positions in the level archive the MAPD data came
+// from, not in the MAPD data itself. The base offset is where the MAPD data started in
+// that archive: the chunk's offset when read from an archive, or the u32 after the
+// magic in a raw MAPD file. An archive chunk is a raw MAPD file without its header, so
+// the header is rebuilt from the chunk's offset (see raw_mapd_data) and both are read
+// the same way. The data is then preceded by the DATA_HEADER_SIZE bytes of magic and
+// base offset, so a stored offset is found at offset - base_offset + DATA_HEADER_SIZE
+pub(crate) const DATA_HEADER_SIZE: u32 = 8;
+// Archive chunk holding the map data
+pub const MAPD_KIND: u32 = 0x4450414D;
+// Start of raw MAPD data, anything else is read as a compressed level archive
+pub const MAP_MAGIC: u32 = 0xdeadc0de;
+// Start of each layer, "LRCS" in file order
+pub const LAYER_MAGIC: u32 = 0x5343524c;
 
-struct Colour {
-    r: u8,
-    g: u8,
-    b: u8,
+// File extensions load_map understands: level archives, plus MAPD data extracted from them
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["lps", "lpc", "lpm", "MAPD"];
+
+pub fn is_supported_extension(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        None => false,
+        Some(extension) => SUPPORTED_EXTENSIONS
+            .iter()
+            .any(|supported| supported.eq_ignore_ascii_case(extension)),
+    }
+}
+
+// Upper bounds on header counts, so corrupt files fail before allocating
+const MAX_LAYERS: u32 = 64;
+const MAX_PALETTE_SIZE: u32 = 1024;
+const MAX_TILE_SIZE: u32 = 256;
+
+// Share of a layer's cells holding one tile id above which the layer is reported as
+// suspiciously uniform, unless LoadOptions sets another threshold
+pub const DEFAULT_UNIFORM_THRESHOLD: f32 = 0.95;
+// Layers smaller than this are often legitimately uniform and aren't checked
+const MIN_UNIFORM_CHECK_CELLS: usize = 64;
+
+#[derive(Debug)]
+pub enum MapError {
+    NoLayers,
+    TooManyLayers(u32),
+    PaletteTooLarge(u32),
+    InvalidOffset(u32),
+    InvalidTileSize { width: u32, height: u32 },
+    LayerTooLarge { width: u32, height: u32 },
+    PaletteIndexOutOfRange(u8),
+    // The tile's data runs past the end of the map data
+    TruncatedTile { offset: u32, expected: u64, available: u64 },
 }
 
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::NoLayers => write!(f, "Map has no layers"),
+            MapError::TooManyLayers(count) => {
+                write!(f, "Map claims {} layers, the maximum is {}", count, MAX_LAYERS)
+            }
+            MapError::PaletteTooLarge(size) => {
+                write!(f, "Map claims {} palette entries, the maximum is {}", size, MAX_PALETTE_SIZE)
+            }
+            MapError::InvalidOffset(offset) => write!(f, "Offset {:#x} is outside of the map data", offset),
+            MapError::InvalidTileSize { width, height } => write!(f, "Invalid tile size {}x{}", width, height),
+            MapError::LayerTooLarge { width, height } => {
+                write!(f, "Layer of {}x{} tiles is larger than the map data", width, height)
+            }
+            MapError::PaletteIndexOutOfRange(index) => {
+                write!(f, "Tile uses palette index {} which is outside of the palette", index)
+            }
+            MapError::TruncatedTile { offset, expected, available } => write!(
+                f,
+                "Tile {:#x} needs {} bytes but the map data ends after {}",
+                offset, expected, available
+            ),
+        }
+    }
+}
+
+impl Error for MapError {}
+
+#[derive(Default)]
+pub struct LoadOptions {
+    // Replaces the palette embedded in the map when decoding tiles
+    pub palette: Option<Vec<Colour>>,
+    // Treat the top bit of each palette entry as a transparency flag. KKnD 2 isn't
+    // known to set it, so it is ignored by default
+    pub palette_alpha: bool,
+    // Load tiles that run past the end of the data as placeholders, in failed_tiles,
+    // instead of failing the whole map
+    pub allow_truncated: bool,
+    // Fraction of cells from 0 to 1, DEFAULT_UNIFORM_THRESHOLD if not set
+    pub uniform_threshold: Option<f32>,
+    // Indices of the layers to load, counted from 0. The others aren't read or decoded,
+    // which saves time and memory on large maps. All layers are loaded if not set
+    pub layers: Option<Vec<usize>>,
+    // Tile width and height used for every layer in place of its header's, for testing
+    // whether a header misreports the size of its tiles
+    pub tile_size: Option<(u32, u32)>,
+    // Read only the tile maps, leaving every layer's tiles empty. Huge maps then open
+    // almost at once, for looking at their layout
+    pub layout_only: bool,
+}
+
+// Tweaks applied when expanding palette indices into RGBA
+#[derive(Clone, Copy, Default)]
+pub struct DecodeOptions {
+    // Decoded colour made transparent in addition to palette index 0, for maps that
+    // use a key colour such as magenta
+    pub transparent_colour: Option<(u8, u8, u8)>,
+    // Added to every non-zero palette index, wrapping at the end of the palette, to
+    // test whether a layer indexes a different part of the palette
+    pub palette_offset: u8,
+    // Colours with a luminance below this fade towards transparent in proportion to
+    // it, for tiles anti-aliased against the game's black background
+    pub dark_threshold: Option<u8>,
+    // Draw palette index 0 in its palette colour instead of transparent, to check
+    // whether a map really uses it as the transparent index
+    pub opaque_index_zero: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Tile {
     pub pixels: Vec<u8>,
+    // The palette indices the pixels were decoded from, as stored in the file
+    pub indices: Vec<u8>,
+}
+
+impl Tile {
+    // Rewrites pixels from indices, so palette changes don't need the file to be read
+    // again. The tile is left as it was if the palette doesn't cover its indices
+    pub fn redecode(&mut self, palette: &[Colour], options: &DecodeOptions) -> Result<(), MapError> {
+        let mut pixels = Vec::<u8>::with_capacity(self.indices.len() * 4);
+
+        for &palette_index in &self.indices {
+            if palette_index == 0 && !options.opaque_index_zero {
+                pixels.extend_from_slice(&[0, 0, 0, 0]);
+                continue;
+            }
+
+            let palette_index = match options.palette_offset {
+                _ if palette_index == 0 => 0,
+                0 => palette_index,
+                offset => ((palette_index as usize + offset as usize) % palette.len().clamp(1, 256)) as u8,
+            };
+            let colour = palette
+                .get(palette_index as usize)
+                .ok_or(MapError::PaletteIndexOutOfRange(palette_index))?;
+
+            let alpha = match options.transparent_colour {
+                Some(key) if key == (colour.r, colour.g, colour.b) => 0,
+                _ => colour.a,
+            };
+            let alpha = match options.dark_threshold {
+                Some(threshold) if colour.luminance() < threshold => {
+                    (alpha as u32 * colour.luminance() as u32 / threshold as u32) as u8
+                }
+                _ => alpha,
+            };
+            pixels.extend_from_slice(&[colour.r, colour.g, colour.b, alpha]);
+        }
+
+        self.pixels = pixels;
+        Ok(())
+    }
 }
 
 pub struct MapLayer {
-    pub map_width: u32,
-    pub map_height: u32,
-    pub tile_width: u32,
-    pub tile_height: u32,
+    // In tiles
+    pub map_size: Dimensions,
+    // In pixels
+    pub tile_size: Dimensions,
     pub tile_map: Vec<u32>,
     pub tiles: HashMap<u32, Tile>,
+    // Tiles that were referenced but couldn't be decoded, they are left out of tiles
+    pub failed_tiles: HashMap<u32, MapError>,
+    // Debug shift applied to this layer's palette indices when re-decoding, 0 as loaded
+    pub palette_offset: u8,
+    // Where the layer's header and tile map were found in the MAPD data. Tiles aren't
+    // included as layers can share them
+    pub data_offset: u64,
+    pub data_len: u64,
+}
+
+// How a layer's cells break down, to tell blank regions by design from decoding bugs
+#[derive(Default)]
+pub struct LayerStats {
+    // Cells with tile id 0, which are intentionally empty
+    pub empty: usize,
+    pub decoded: usize,
+    pub missing: usize,
+}
+
+impl MapLayer {
+    // Every decoded tile in the layer as (tile id, tile), in no particular order
+    pub fn iter_tiles(&self) -> impl Iterator<Item = (u32, &Tile)> {
+        self.tiles.iter().map(|(tile_id, tile)| (*tile_id, tile))
+    }
+
+    pub fn pixel_size(&self) -> Dimensions {
+        self.map_size.pixel_size(self.tile_size)
+    }
+
+    // Tile id of the cell at (x, y) in tiles, 0 for an empty cell, or None outside the map
+    pub fn tile_at(&self, x: u32, y: u32) -> Option<u32> {
+        if !self.map_size.contains(x, y) {
+            return None;
+        }
+        self.tile_map.get(self.map_size.index_of(x, y)).copied()
+    }
+
+    // Stores a tile id in the cell at (x, y) and returns the id it replaces, or None if
+    // the cell is outside the map. The tile itself has to be in tiles to be drawn
+    pub fn set_tile(&mut self, x: u32, y: u32, tile_id: u32) -> Option<u32> {
+        if !self.map_size.contains(x, y) {
+            return None;
+        }
+        let cell = self.tile_map.get_mut(self.map_size.index_of(x, y))?;
+        Some(std::mem::replace(cell, tile_id))
+    }
+
+    // Average position of the non-empty cells, in tiles, or None if the layer is empty
+    pub fn content_centroid(&self) -> Option<(f32, f32)> {
+        let (mut sum_x, mut sum_y, mut count) = (0.0f64, 0.0f64, 0usize);
+        for (position, tile_index) in self.tile_map.iter().enumerate() {
+            if *tile_index != 0 {
+                let (x, y) = self.map_size.position_of(position);
+                sum_x += x as f64 + 0.5;
+                sum_y += y as f64 + 0.5;
+                count += 1;
+            }
+        }
+
+        (count > 0).then(|| ((sum_x / count as f64) as f32, (sum_y / count as f64) as f32))
+    }
+
+    // Smallest block of cells that holds every non-empty cell, as (min, max) in tiles
+    // with max exclusive, or None if the layer is empty
+    pub fn content_bounds(&self) -> Option<((u32, u32), (u32, u32))> {
+        let mut bounds: Option<((u32, u32), (u32, u32))> = None;
+        for (position, tile_index) in self.tile_map.iter().enumerate() {
+            if *tile_index == 0 {
+                continue;
+            }
+
+            let (x, y) = self.map_size.position_of(position);
+            bounds = Some(match bounds {
+                None => ((x, y), (x + 1, y + 1)),
+                Some((min, max)) => ((min.0.min(x), min.1.min(y)), (max.0.max(x + 1), max.1.max(y + 1))),
+            });
+        }
+        bounds
+    }
+
+    // The most common tile id, empty cells included, and the fraction of cells holding it
+    pub fn dominant_tile(&self) -> Option<(u32, f32)> {
+        let mut counts = HashMap::<u32, usize>::new();
+        for tile_index in &self.tile_map {
+            *counts.entry(*tile_index).or_default() += 1;
+        }
+
+        counts
+            .into_iter()
+            .max_by_key(|(tile_index, count)| (*count, std::cmp::Reverse(*tile_index)))
+            .map(|(tile_index, count)| (tile_index, count as f32 / self.tile_map.len() as f32))
+    }
+
+    // The dominant tile and its share of the cells if it covers more than threshold of
+    // them, which suggests the layer didn't parse correctly. Small layers aren't judged
+    pub fn uniform_tile(&self, threshold: f32) -> Option<(u32, f32)> {
+        if self.tile_map.len() < MIN_UNIFORM_CHECK_CELLS {
+            return None;
+        }
+        self.dominant_tile().filter(|(_, fraction)| *fraction > threshold)
+    }
+
+    // How many cells use each tile id, empty cells are not counted
+    pub fn tile_usage(&self) -> HashMap<u32, u32> {
+        let mut usage = HashMap::<u32, u32>::new();
+        for tile_index in self.tile_map.iter().filter(|tile_index| **tile_index != 0) {
+            *usage.entry(*tile_index).or_default() += 1;
+        }
+        usage
+    }
+
+    pub fn stats(&self) -> LayerStats {
+        let mut stats = LayerStats::default();
+        for tile_index in &self.tile_map {
+            if *tile_index == 0 {
+                stats.empty += 1;
+            } else if self.tiles.contains_key(tile_index) {
+                stats.decoded += 1;
+            } else {
+                stats.missing += 1;
+            }
+        }
+        stats
+    }
 }
 
 pub struct Map {
     pub layers: Vec<MapLayer>,
+    // The palette embedded in the map, before any override is applied
+    pub palette: Vec<Colour>,
 }
 
-fn read_raw_tile<R: Read + Seek>(
-    reader: &mut BufReader<R>,
-    offset: u64,
-    width: u32,
-    height: u32,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let saved_stream_position = reader.stream_position()?;
-    reader.seek(SeekFrom::Start(offset))?;
+impl Map {
+    // Every decoded tile as (layer index, tile id, tile). Layers are visited in order,
+    // the tiles within a layer in no particular order. A tile shared by several layers
+    // is yielded once for each of them
+    pub fn iter_tiles(&self) -> impl Iterator<Item = (usize, u32, &Tile)> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(l, layer)| layer.iter_tiles().map(move |(tile_id, tile)| (l, tile_id, tile)))
+    }
 
-    let size = (width * height) as usize;
-    let mut buffer = Vec::<u8>::with_capacity(size);
-    buffer.resize(size, 0);
-    reader.read_exact(buffer.as_mut_slice())?;
-    reader.seek(SeekFrom::Start(saved_stream_position))?;
-    Ok(buffer)
-}
+    // Size in pixels of the largest layer, layers don't have to share a tile size
+    pub fn pixel_size(&self) -> Dimensions {
+        self.layers.iter().fold(Dimensions::default(), |size, layer| size.max(layer.pixel_size()))
+    }
 
-fn create_tile_from_raw(data: &Vec<u8>, palette: &Vec<Colour>) -> Result<Tile, Box<dyn Error>> {
-    let mut pixels = Vec::<u8>::with_capacity(data.len());
+    // Bounding box in pixels of the non-empty cells across all layers, as (min, max)
+    // with max exclusive, or None if every layer is empty. Used to skip empty margins
+    pub fn content_bounds(&self) -> Option<((u32, u32), (u32, u32))> {
+        self.layers
+            .iter()
+            .filter_map(|layer| {
+                let (min, max) = layer.content_bounds()?;
+                let min = Dimensions::new(min.0, min.1).pixel_size(layer.tile_size);
+                let max = Dimensions::new(max.0, max.1).pixel_size(layer.tile_size);
+                Some(((min.width, min.height), (max.width, max.height)))
+            })
+            .reduce(|(min_a, max_a), (min_b, max_b)| {
+                ((min_a.0.min(min_b.0), min_a.1.min(min_b.1)), (max_a.0.max(max_b.0), max_a.1.max(max_b.1)))
+            })
+    }
 
-    for i in 0..data.len() {
-        let palette_index = data[i] as usize;
+    // Every referenced tile as (tile id, length in bytes), sorted by id and including
+    // tiles that failed to decode. Tile ids are offsets into the map data, so the gaps
+    // between spans show how the tiles are packed
+    pub fn tile_spans(&self) -> Vec<(u32, u32)> {
+        let mut spans: Vec<(u32, u32)> = self
+            .layers
+            .iter()
+            .flat_map(|layer| {
+                let length = layer.tile_size.area() as u32;
+                layer.tile_map.iter().copied().filter(|tile_id| *tile_id != 0).map(move |tile_id| (tile_id, length))
+            })
+            .collect();
+        spans.sort_unstable();
+        spans.dedup();
+        spans
+    }
 
-        if palette_index == 0 {
-            pixels.push(0);
-            pixels.push(0);
-            pixels.push(0);
-            pixels.push(0);
-            continue;
+    // Sets a cell of a layer to a tile, copying the tile in from another layer with the
+    // same tile size if the layer doesn't have it, so it is drawn and saved with the
+    // layer. Returns the id the cell held, or None if the cell is outside the layer,
+    // already holds the tile, or no layer of that tile size has it
+    pub fn paint_tile(&mut self, layer: usize, x: u32, y: u32, tile_id: u32) -> Option<u32> {
+        let tile_size = self.layers.get(layer)?.tile_size;
+        if self.layers[layer].tile_at(x, y)? == tile_id {
+            return None;
         }
+        // Only from a layer with the same tile size, a different size wouldn't fit the cell
+        let tile = self
+            .layers
+            .iter()
+            .filter(|other| other.tile_size == tile_size)
+            .find_map(|other| other.tiles.get(&tile_id))?
+            .clone();
 
-        pixels.push(palette[palette_index].r);
-        pixels.push(palette[palette_index].g);
-        pixels.push(palette[palette_index].b);
-        pixels.push(0xff);
+        let layer = &mut self.layers[layer];
+        layer.tiles.entry(tile_id).or_insert(tile);
+        layer.set_tile(x, y, tile_id)
     }
 
-    Ok(Tile { pixels })
+    // The embedded palette with an override applied on top, as used when loading
+    pub fn effective_palette(&self, palette_override: Option<&[Colour]>) -> Vec<Colour> {
+        let mut palette = self.palette.clone();
+        if let Some(palette_override) = palette_override {
+            apply_palette_override(&mut palette, palette_override);
+        }
+        palette
+    }
+
+    // Merges tiles that are stored more than once under different offsets, keeping the
+    // lowest tile id of each and pointing the tile maps at it. Returns the number of
+    // tile ids merged away. Tiles are compared by their palette indices rather than
+    // their pixels, so merged tiles stay identical when re-decoded with another palette
+    pub fn deduplicate_tiles(&mut self) -> usize {
+        // Ids are file offsets, so the same id in two layers is the same tile unless
+        // the layers read it with different tile sizes
+        let mut tiles: Vec<(u32, Dimensions, &[u8])> = self
+            .layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .iter_tiles()
+                    .map(|(tile_id, tile)| (tile_id, layer.tile_size, tile.indices.as_slice()))
+            })
+            .collect();
+        tiles.sort_unstable_by_key(|(tile_id, tile_size, _)| (*tile_id, *tile_size));
+        tiles.dedup_by_key(|(tile_id, tile_size, _)| (*tile_id, *tile_size));
+
+        let mut canonical_ids = HashMap::<(Dimensions, &[u8]), u32>::new();
+        let mut remap = HashMap::<(u32, Dimensions), u32>::new();
+        for (tile_id, tile_size, indices) in tiles {
+            let canonical_id = *canonical_ids.entry((tile_size, indices)).or_insert(tile_id);
+            if canonical_id != tile_id {
+                remap.insert((tile_id, tile_size), canonical_id);
+            }
+        }
+
+        for layer in &mut self.layers {
+            let tile_size = layer.tile_size;
+
+            for tile_id in layer.tile_map.iter_mut() {
+                if let Some(canonical_id) = remap.get(&(*tile_id, tile_size)) {
+                    *tile_id = *canonical_id;
+                }
+            }
+
+            let duplicate_ids: Vec<u32> = layer
+                .tiles
+                .keys()
+                .copied()
+                .filter(|tile_id| remap.contains_key(&(*tile_id, tile_size)))
+                .collect();
+            for tile_id in duplicate_ids {
+                let tile = layer.tiles.remove(&tile_id).unwrap();
+                layer.tiles.entry(remap[&(tile_id, tile_size)]).or_insert(tile);
+            }
+        }
+
+        remap.len()
+    }
+
+    // Re-expands every tile with a new palette. Tiles the palette can't decode are
+    // moved to failed_tiles, the same as when loading
+    pub fn redecode(&mut self, palette: &[Colour], options: &DecodeOptions) {
+        for layer in &mut self.layers {
+            let options = DecodeOptions {
+                palette_offset: layer.palette_offset,
+                ..*options
+            };
+            let failed: Vec<(u32, MapError)> = layer
+                .tiles
+                .par_iter_mut()
+                .filter_map(|(offset, tile)| tile.redecode(palette, &options).err().map(|e| (*offset, e)))
+                .collect();
+
+            for (offset, e) in failed {
+                layer.tiles.remove(&offset);
+                layer.failed_tiles.insert(offset, e);
+            }
+        }
+    }
+
+    // Checks that every tile referenced by a layer's tile map was decoded, a
+    // dangling reference points at a mistake in the tile offset arithmetic
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::<String>::new();
+
+        for (l, layer) in self.layers.iter().enumerate() {
+            let expected_size = layer.map_size.area();
+            if layer.tile_map.len() as u64 != expected_size {
+                problems.push(format!(
+                    "Layer {}: tile map has {} entries, expected {}",
+                    l,
+                    layer.tile_map.len(),
+                    expected_size
+                ));
+            }
+
+            for (position, tile_index) in layer.tile_map.iter().enumerate() {
+                if *tile_index != 0 && !layer.tiles.contains_key(tile_index) {
+                    let reason = match layer.failed_tiles.get(tile_index) {
+                        Some(e) => format!("failed to decode: {}", e),
+                        None => "has not been loaded".to_string(),
+                    };
+                    let (x, y) = layer.map_size.position_of(position);
+                    problems.push(format!("Layer {}: tile {:#x} at {}, {} {}", l, tile_index, x, y, reason));
+                }
+            }
+        }
+
+        problems
+    }
 }
 
-fn read_layer<R: Read + Seek>(
-    reader: &mut BufReader<R>,
-    file_offsets: u32,
-    palette: &Vec<Colour>,
-) -> Result<MapLayer, Box<dyn Error>> {
+// Converts an offset stored in the file into a position in the reader, failing if it
+// lands before the start of the map data or past its end
+pub(crate) fn data_position(offset: u32, base_offset: u32, data_length: u64) -> Result<u64, MapError> {
+    (offset as u64 + DATA_HEADER_SIZE as u64)
+        .checked_sub(base_offset as u64)
+        .filter(|position| *position < data_length)
+        .ok_or(MapError::InvalidOffset(offset))
+}
+
+// Position of a tile's palette indices, failing if the tile starts outside the data or
+// runs past its end
+pub(crate) fn tile_position(
+    offset: u32,
+    base_offset: u32,
+    data_length: u64,
+    tile_length: u64,
+) -> Result<u64, MapError> {
+    let position = data_position(offset, base_offset, data_length)?;
+    if position + tile_length > data_length {
+        return Err(MapError::TruncatedTile { offset, expected: tile_length, available: data_length - position });
+    }
+    Ok(position)
+}
+
+// The map header after the magic and base offset, as stored. Shared by parse_map and
+// borrowed::parse_map_ref so the two can't disagree about the layout
+pub(crate) struct MapHeader {
+    pub layer_offsets: Vec<u32>,
+    // Packed 15-bit colours, each parser decides how to expand them
+    pub palette: Vec<u16>,
+}
+
+// Reads the map header from just after the magic and base offset, leaving the reader
+// at the end of the palette
+pub(crate) fn read_map_header<R: Read>(reader: &mut R) -> Result<MapHeader, Box<dyn Error>> {
+    // Skip some unknown data (probably a version number)
+    reader.read_u32::<LittleEndian>()?;
+    let layers = reader.read_u32::<LittleEndian>()?;
+
+    // The viewer and renderer size everything from the first layer
+    if layers == 0 {
+        return Err(MapError::NoLayers.into());
+    }
+
+    if layers > MAX_LAYERS {
+        return Err(MapError::TooManyLayers(layers).into());
+    }
+
+    let mut layer_offsets = Vec::<u32>::with_capacity(layers as usize);
+    for _i in 0..layers {
+        layer_offsets.push(reader.read_u32::<LittleEndian>()?);
+    }
+    debug!(
+        "{} layers at offsets {}",
+        layers,
+        layer_offsets.iter().map(|offset| format!("{:#x}", offset)).collect::<Vec<_>>().join(", ")
+    );
+
+    let palette_size = reader.read_u32::<LittleEndian>()?;
+    if palette_size > MAX_PALETTE_SIZE {
+        return Err(MapError::PaletteTooLarge(palette_size).into());
+    }
+    debug!("Palette has {} colours", palette_size);
+
+    let mut palette = Vec::<u16>::with_capacity(palette_size as usize);
+    for _i in 0..palette_size {
+        palette.push(reader.read_u16::<LittleEndian>()?);
+    }
+
+    Ok(MapHeader { layer_offsets, palette })
+}
+
+pub(crate) struct LayerHeader {
+    // In tiles
+    pub map_size: Dimensions,
+    // In pixels
+    pub tile_size: Dimensions,
+}
+
+// Reads the header of layer index from its magic at position, where the reader is,
+// leaving the reader at the start of the tile map. tile_size replaces the stored tile
+// size, see LoadOptions::tile_size
+pub(crate) fn read_layer_header<R: Read>(
+    reader: &mut R,
+    index: usize,
+    position: u64,
+    tile_size: Option<(u32, u32)>,
+) -> Result<LayerHeader, Box<dyn Error>> {
+    let layer_magic = reader.read_u32::<LittleEndian>()?;
+    if layer_magic != LAYER_MAGIC {
+        return Err(format!(
+            "Layer {}: Invalid magic {:#x} ({}) at offset {:#x}, expected {:#x} ({})",
+            index,
+            layer_magic,
+            fourcc_to_string(layer_magic),
+            position,
+            LAYER_MAGIC,
+            fourcc_to_string(LAYER_MAGIC)
+        )
+        .into());
+    }
+    debug!("Layer {}: magic ok at data position {:#x}", index, position);
+
     let tile_width = reader.read_u32::<LittleEndian>()?;
     let tile_height = reader.read_u32::<LittleEndian>()?;
     let map_width = reader.read_u32::<LittleEndian>()?;
     let map_height = reader.read_u32::<LittleEndian>()?;
+    debug!("Layer is {}x{} tiles of {}x{} pixels", map_width, map_height, tile_width, tile_height);
 
     // Skip some unknown data
     // FIXME: not unknown now
     // it is layer_width_pixels, layer_height_pixels, then something unknown
-    reader.seek_relative(12)?;
+    let mut skipped = [0; 12];
+    reader.read_exact(&mut skipped)?;
 
-    let map_size = (map_width * map_height) as usize;
-    let mut tile_map: Vec<u32> = Vec::with_capacity(map_size);
+    let (tile_width, tile_height) = match tile_size {
+        None => (tile_width, tile_height),
+        Some((width, height)) => {
+            warn!("Reading tiles as {}x{} pixels, overriding the layer's {}x{}", width, height, tile_width, tile_height);
+            (width, height)
+        }
+    };
 
-    let mut tiles = HashMap::<u32, Tile>::new();
+    if tile_width == 0 || tile_height == 0 || tile_width > MAX_TILE_SIZE || tile_height > MAX_TILE_SIZE {
+        return Err(MapError::InvalidTileSize { width: tile_width, height: tile_height }.into());
+    }
 
-    for _i in 0..map_size {
+    Ok(LayerHeader {
+        map_size: Dimensions::new(map_width, map_height),
+        tile_size: Dimensions::new(tile_width, tile_height),
+    })
+}
+
+// Reads a layer's tile map, with tile ids rounded down to a multiple of 4. remaining is
+// the number of bytes left in the data from the reader's position
+pub(crate) fn read_tile_map<R: Read>(
+    reader: &mut R,
+    map_size: Dimensions,
+    remaining: u64,
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    // Every tile map entry is a u32, so the layer can't claim more entries than the data holds
+    let cells = map_size.width as u64 * map_size.height as u64;
+    if cells > remaining / 4 {
+        return Err(MapError::LayerTooLarge { width: map_size.width, height: map_size.height }.into());
+    }
+
+    let mut tile_map: Vec<u32> = Vec::with_capacity(cells as usize);
+    for _i in 0..cells {
         let tile_id = reader.read_u32::<LittleEndian>()?;
         tile_map.push(tile_id - (tile_id % 4));
+    }
+    Ok(tile_map)
+}
 
-        let offset = tile_id - (tile_id % 4);
+fn remaining_bytes<R: Read + Seek>(reader: &mut BufReader<R>) -> Result<u64, Box<dyn Error>> {
+    let position = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(position))?;
+    Ok(end.saturating_sub(position))
+}
 
-        if offset == 0 {
-            continue;
-        }
+// Reads a tile's palette indices, leaving the reader positioned after it
+fn read_raw_tile<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    offset: u64,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Seeking relative to the current position keeps the BufReader's buffer when
+    // the tile is close by, which it usually is as tiles are read in file order
+    let position = reader.stream_position()?;
+    reader.seek_relative(offset as i64 - position as i64)?;
+
+    let size = (width * height) as usize;
+    let mut buffer = vec![0; size];
+    reader.read_exact(buffer.as_mut_slice())?;
+    Ok(buffer)
+}
 
-        if !tiles.contains_key(&offset) {
-            let raw_tile = read_raw_tile(
-                &mut *reader,
-                (offset + DATA_HEADER_SIZE - file_offsets) as u64,
-                tile_width,
-                tile_height,
-            )?;
-            let tile = create_tile_from_raw(&raw_tile, &palette)?;
-            tiles.insert(offset, tile);
+fn create_tile_from_raw(data: &[u8], palette: &[Colour]) -> Result<Tile, MapError> {
+    let mut tile = Tile { pixels: Vec::new(), indices: data.to_vec() };
+    tile.redecode(palette, &DecodeOptions::default())?;
+    Ok(tile)
+}
+
+// position is where the layer's magic is, and the reader with it
+fn read_layer<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    index: usize,
+    position: u64,
+    base_offset: u32,
+    data_length: u64,
+    palette: &[Colour],
+    options: &LoadOptions,
+) -> Result<MapLayer, Box<dyn Error>> {
+    let LayerHeader { map_size, tile_size } = read_layer_header(reader, index, position, options.tile_size)?;
+    let remaining = remaining_bytes(reader)?;
+    let tile_map = read_tile_map(reader, map_size, remaining)?;
+    let data_len = reader.stream_position()? - position;
+
+    // Read each unique tile once, in file order, so the reader only ever moves forwards
+    let mut offsets: Vec<u32> = tile_map.iter().copied().filter(|offset| *offset != 0).collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    debug!("Layer references {} unique tiles", offsets.len());
+    if options.layout_only {
+        offsets.clear();
+    }
+
+    let tile_length = tile_size.area();
+    let mut raw_tiles = Vec::<(u32, Vec<u8>)>::with_capacity(offsets.len());
+    let mut truncated_tiles = Vec::<(u32, MapError)>::new();
+    for offset in offsets {
+        let position = match tile_position(offset, base_offset, data_length, tile_length) {
+            Ok(position) => position,
+            Err(e @ MapError::TruncatedTile { .. }) if options.allow_truncated => {
+                warn!("{}, loading it as a placeholder", e);
+                truncated_tiles.push((offset, e));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let raw_tile = read_raw_tile(reader, position, tile_size.width, tile_size.height)?;
+        raw_tiles.push((offset, raw_tile));
+    }
+
+    // Reading has to be sequential, but expanding palette indices into RGBA is
+    // independent per tile
+    let decoded: Vec<(u32, Result<Tile, MapError>)> = raw_tiles
+        .par_iter()
+        .map(|(offset, raw_tile)| (*offset, create_tile_from_raw(raw_tile, palette)))
+        .collect();
+
+    // A tile that doesn't decode shouldn't stop the rest of the map from loading
+    let mut tiles = HashMap::<u32, Tile>::with_capacity(decoded.len());
+    let mut failed_tiles: HashMap<u32, MapError> = truncated_tiles.into_iter().collect();
+    for (offset, tile) in decoded {
+        match tile {
+            Ok(tile) => {
+                tiles.insert(offset, tile);
+            }
+            Err(e) => {
+                failed_tiles.insert(offset, e);
+            }
         }
     }
 
     Ok(MapLayer {
-        map_width,
-        map_height,
-        tile_width,
-        tile_height,
+        map_size,
+        tile_size,
         tile_map,
         tiles,
+        failed_tiles,
+        palette_offset: 0,
+        data_offset: position,
+        data_len,
     })
 }
 
+// A misparse often reads as a layer of all zeros or one repeated id, rather than
+// failing outright. Real maps vary enough that this is rare by design
+fn warn_if_uniform(index: usize, layer: &MapLayer, threshold: f32) {
+    if let Some((tile_index, fraction)) = layer.uniform_tile(threshold) {
+        warn!(
+            "Layer {}: {:.1}% of cells are tile {:#x}, the map may not have parsed correctly",
+            index,
+            fraction * 100.0,
+            tile_index
+        );
+    }
+}
+
+// Layer headers and tile maps have no reason to share bytes, so an overlap suggests an
+// offset or size was misread. Indices are of the loaded layers
+fn warn_if_overlapping(layers: &[MapLayer]) {
+    let mut ranges: Vec<(usize, u64, u64)> = layers
+        .iter()
+        .enumerate()
+        .map(|(l, layer)| (l, layer.data_offset, layer.data_offset + layer.data_len))
+        .collect();
+    ranges.sort_unstable_by_key(|(_, start, _)| *start);
+    for pair in ranges.windows(2) {
+        let ((first, _, first_end), (second, second_start, _)) = (pair[0], pair[1]);
+        if second_start < first_end {
+            warn!(
+                "Layers {} and {} overlap by {} bytes at {:#x}, the map may not have parsed correctly",
+                first,
+                second,
+                first_end - second_start,
+                second_start
+            );
+        }
+    }
+}
+
+fn apply_palette_override(palette: &mut [Colour], palette_override: &[Colour]) {
+    if palette_override.len() != palette.len() {
+        warn!(
+            "Palette override has {} colours but the map palette has {}",
+            palette_override.len(),
+            palette.len()
+        );
+    }
+
+    // Keep the embedded colours for any entries the override doesn't cover. Override
+    // files only hold RGB, so transparency still comes from the map
+    for (colour, replacement) in palette.iter_mut().zip(palette_override) {
+        *colour = Colour { a: colour.a, ..*replacement };
+    }
+}
+
+// Parses MAPD data from just after its 8 byte header. base_offset is where the data
+// started in its level archive, see DATA_HEADER_SIZE
 pub fn parse_map<R: Read + Seek>(
     reader: &mut BufReader<R>,
-    file_offsets: u32,
+    base_offset: u32,
+    options: &LoadOptions,
 ) -> Result<Map, Box<dyn Error>> {
-    // Skip some unknown data (probably a version number)
-    reader.seek_relative(4)?;
-    let layers = reader.read_u32::<LittleEndian>()?;
+    let data_length = reader.stream_position()? + remaining_bytes(reader)?;
 
-    let mut layer_offsets = Vec::<u64>::new();
-    for _i in 0..layers {
-        let layer_offset = reader.read_u32::<LittleEndian>()?;
-        layer_offsets.push(layer_offset as u64);
-    }
+    let MapHeader { layer_offsets, palette } = read_map_header(reader)?;
+    let mut palette: Vec<Colour> = palette
+        .into_iter()
+        .map(|colour_packed| {
+            if options.palette_alpha {
+                Colour::from_packed_with_alpha(colour_packed)
+            } else {
+                Colour::from_packed(colour_packed)
+            }
+        })
+        .collect();
 
-    let palette_size = reader.read_u32::<LittleEndian>()?;
+    let embedded_palette = palette.clone();
+    if let Some(palette_override) = &options.palette {
+        apply_palette_override(&mut palette, palette_override);
+    }
 
-    let mut palette: Vec<Colour> = Vec::with_capacity(palette_size as usize);
-    for _i in 0..palette_size as usize {
-        let colour_packed = reader.read_u16::<LittleEndian>()?;
-        let colour = Colour {
-            r: (((colour_packed & 0x7c00) >> 7) & 0xff) as u8,
-            g: (((colour_packed & 0x03e0) >> 2) & 0xff) as u8,
-            b: (((colour_packed & 0x001f) << 3) & 0xff) as u8,
-        };
-        palette.push(colour);
+    if let Some(&layer) = options.layers.iter().flatten().find(|layer| **layer >= layer_offsets.len()) {
+        return Err(format!("Layer {} was requested but the map only has {} layers", layer, layer_offsets.len()).into());
     }
 
     let mut map_layers = Vec::<MapLayer>::new();
 
-    for i in 0..layers as usize {
-        reader.seek(SeekFrom::Start(layer_offsets[i] + DATA_HEADER_SIZE as u64 - file_offsets as u64))?;
-
-        let layer_magic = reader.read_u32::<LittleEndian>()?;
-        if layer_magic != 0x5343524c {
-            return Err(format!("Layer {}: Invalid magic {:#x} at offset {:?}", i, layer_magic, reader.stream_position()).into());
+    for (i, layer_offset) in layer_offsets.iter().enumerate() {
+        if !options.layers.as_ref().is_none_or(|layers| layers.contains(&i)) {
+            debug!("Layer {}: skipped", i);
+            continue;
         }
 
-        let layer = read_layer(&mut *reader, file_offsets, &palette)?;
+        let position = data_position(*layer_offset, base_offset, data_length)?;
+        reader.seek(SeekFrom::Start(position))?;
+
+        let layer = read_layer(reader, i, position, base_offset, data_length, &palette, options)?;
+        debug!("Layer {}: data at {:#x}, {} bytes", i, layer.data_offset, layer.data_len);
+        warn_if_uniform(i, &layer, options.uniform_threshold.unwrap_or(DEFAULT_UNIFORM_THRESHOLD));
         map_layers.push(layer);
     }
 
-    Ok(Map { layers: map_layers })
+    // Only reachable with an empty layer selection
+    if map_layers.is_empty() {
+        return Err(MapError::NoLayers.into());
+    }
+    warn_if_overlapping(&map_layers);
+
+    Ok(Map { layers: map_layers, palette: embedded_palette })
 }
 
-pub fn load_map(path: &PathBuf) -> Result<Map, Box<dyn Error>> {
-    let file = File::open(&path).map_err(|e| format!("Failed to open file: {}", e))?;
-    let mut reader = BufReader::new(file);
+// Turns a MAPD chunk extracted from a level archive into the raw MAPD file it would
+// be saved as, by putting back the magic and recording the chunk's offset as the base
+// offset. Loading either gives the same map
+pub fn raw_mapd_data(base_offset: u32, chunk: &[u8]) -> Vec<u8> {
+    let mut data = Vec::<u8>::with_capacity(DATA_HEADER_SIZE as usize + chunk.len());
+    data.extend_from_slice(&MAP_MAGIC.to_le_bytes());
+    data.extend_from_slice(&base_offset.to_le_bytes());
+    data.extend_from_slice(chunk);
+    data
+}
 
+pub fn load_map_from_reader<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    options: &LoadOptions,
+) -> Result<Map, Box<dyn Error>> {
     let magic = reader.read_u32::<LittleEndian>()?;
 
     match magic {
-        0xdeadc0de => {
-            let file_offsets = reader.read_u32::<LittleEndian>()?;
-            parse_map(&mut reader, file_offsets)
+        MAP_MAGIC => {
+            let base_offset = reader.read_u32::<LittleEndian>()?;
+            debug!("Raw MAPD data, extracted from offset {:#x} of its archive", base_offset);
+            parse_map(reader, base_offset, options)
         }
         _ => {
-            let decompressed_data = decompress(&path)?;
+            debug!("Magic {:#x}, reading as a compressed level archive", magic);
+            let decompressed_data = decompress(reader)?;
             let files = unpack(&decompressed_data.archive)?;
 
+            let mut kinds = Vec::<u32>::new();
             let mut map_file: Option<FileEntry> = Option::None;
 
             for file in files {
-                if file.kind == 0x4450414D {
+                if file.kind == MAPD_KIND {
                     map_file = Option::from(file);
                     break;
                 }
+                if !kinds.contains(&file.kind) {
+                    kinds.push(file.kind);
+                }
             }
 
             match map_file {
-                None => Err(format!("No MAPD data found in file: {:?}", path).into()),
+                None if kinds.is_empty() => Err("No MAPD data found in archive, it contains no files".into()),
+                None => {
+                    let kinds: Vec<String> = kinds.into_iter().map(fourcc_to_string).collect();
+                    Err(format!("No MAPD data found in archive, it contains: {}", kinds.join(", ")).into())
+                }
                 Some(entry) => {
-                    let mut padding = Vec::<u8>::new();
-                    padding.resize(8, 0);
+                    debug!("Found MAPD data at offset {:#x}", entry.offset);
+                    let chunk = unpack::extract_file(&decompressed_data.archive, &entry)?;
+                    let mut reader = BufReader::new(Cursor::new(raw_mapd_data(entry.offset, &chunk)));
+                    load_map_from_reader(&mut reader, options)
+                }
+            }
+        }
+    }
+}
 
-                    let data = [padding,
-                        unpack::extract_file(&decompressed_data.archive, &entry)?].concat();
+pub fn load_map(path: &Path, options: &LoadOptions) -> Result<Map, Box<dyn Error>> {
+    load_map_from(path, options)
+}
 
-                    let cursor = Cursor::new(data);
-                    let mut cursor_reader = BufReader::new(cursor);
+pub fn load_map_from_zip(zip_path: &Path, entry_name: &str, options: &LoadOptions) -> Result<Map, Box<dyn Error>> {
+    load_map_from(&ZipEntry { archive: zip_path, entry: entry_name }, options)
+}
 
-                    cursor_reader.seek_relative(8)?;
-                    parse_map(&mut cursor_reader, entry.offset)
-                }
+// Size of a layer block: magic, four sizes, 12 bytes of pixel size and unknown data,
+// then the tile map
+fn layer_block_size(layer: &MapLayer) -> u64 {
+    4 + 16 + 12 + layer.tile_map.len() as u64 * 4
+}
+
+// Serialises a map as raw MAPD data that load_map reads back into the same map,
+// with the given palette in place of the embedded one. Tile ids are file offsets,
+// so each tile is written where its id points, after the header and before the
+// layers. Tiles that failed to decode have no indices left and are written as zeros
+pub fn write_map<W: Write>(writer: &mut W, map: &Map, palette: &[Colour]) -> Result<(), Box<dyn Error>> {
+    if map.layers.is_empty() {
+        return Err(MapError::NoLayers.into());
+    }
+    if palette.len() > MAX_PALETTE_SIZE as usize {
+        return Err(MapError::PaletteTooLarge(palette.len() as u32).into());
+    }
+
+    let header_size = 16 + map.layers.len() as u64 * 4 + 4 + palette.len() as u64 * 2;
+
+    // Pick base_offset so that the lowest tile id lands right after the header
+    let min_tile_id = map.layers.iter().flat_map(|layer| layer.tile_map.iter()).filter(|id| **id != 0).min();
+    let base_offset = match min_tile_id {
+        None => DATA_HEADER_SIZE as u64,
+        Some(&min_tile_id) => (min_tile_id as u64 + DATA_HEADER_SIZE as u64)
+            .checked_sub(header_size)
+            .ok_or(MapError::InvalidOffset(min_tile_id))?,
+    };
+    let position_of = |id: u32| id as u64 + DATA_HEADER_SIZE as u64 - base_offset;
+    let id_of = |position: u64| {
+        u32::try_from(position + base_offset - DATA_HEADER_SIZE as u64).map_err(|_| "Map is too large to save")
+    };
+
+    let tiles_end = map
+        .layers
+        .iter()
+        .flat_map(|layer| {
+            let tile_bytes = layer.tile_size.area();
+            layer.tile_map.iter().filter(|id| **id != 0).map(move |id| position_of(*id) + tile_bytes)
+        })
+        .max()
+        .unwrap_or(header_size);
+
+    let mut layer_positions = Vec::<u64>::with_capacity(map.layers.len());
+    let mut end = tiles_end;
+    for layer in &map.layers {
+        layer_positions.push(end);
+        end += layer_block_size(layer);
+    }
+
+    let mut data = vec![0u8; end as usize];
+    let mut header = Cursor::new(&mut data[..]);
+    header.write_u32::<LittleEndian>(MAP_MAGIC)?;
+    header.write_u32::<LittleEndian>(u32::try_from(base_offset).map_err(|_| "Map is too large to save")?)?;
+    header.write_u32::<LittleEndian>(0)?;
+    header.write_u32::<LittleEndian>(map.layers.len() as u32)?;
+    for position in &layer_positions {
+        header.write_u32::<LittleEndian>(id_of(*position)?)?;
+    }
+    header.write_u32::<LittleEndian>(palette.len() as u32)?;
+    for colour in palette {
+        header.write_u16::<LittleEndian>(colour.to_packed())?;
+    }
+
+    for (layer, position) in map.layers.iter().zip(&layer_positions) {
+        for (tile_id, tile) in layer.iter_tiles() {
+            let start = position_of(tile_id) as usize;
+            data[start..start + tile.indices.len()].copy_from_slice(&tile.indices);
+        }
+
+        let mut block = Cursor::new(&mut data[*position as usize..]);
+        block.write_u32::<LittleEndian>(LAYER_MAGIC)?;
+        block.write_u32::<LittleEndian>(layer.tile_size.width)?;
+        block.write_u32::<LittleEndian>(layer.tile_size.height)?;
+        block.write_u32::<LittleEndian>(layer.map_size.width)?;
+        block.write_u32::<LittleEndian>(layer.map_size.height)?;
+        let pixel_size = layer.pixel_size();
+        block.write_u32::<LittleEndian>(pixel_size.width)?;
+        block.write_u32::<LittleEndian>(pixel_size.height)?;
+        block.write_u32::<LittleEndian>(0)?;
+        for tile_id in &layer.tile_map {
+            block.write_u32::<LittleEndian>(*tile_id)?;
+        }
+    }
+
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+pub fn save_map(path: &Path, map: &Map, palette: &[Colour]) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path).map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+    write_map(&mut writer, map, palette)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{raw_map, TestLayer, BASE_OFFSET};
+
+    fn load(data: Vec<u8>, options: &LoadOptions) -> Result<Map, Box<dyn Error>> {
+        load_map_from_reader(&mut BufReader::new(Cursor::new(data)), options)
+    }
+
+    fn map_error(result: Result<Map, Box<dyn Error>>) -> MapError {
+        match result {
+            Ok(_) => panic!("Map loaded, expected an error"),
+            Err(e) => *e.downcast::<MapError>().unwrap_or_else(|e| panic!("Expected a MapError, got: {}", e)),
+        }
+    }
+
+    #[test]
+    fn zero_layers_is_rejected() {
+        let data = raw_map(&[0, 0x7fff], &[], &[]);
+        assert!(matches!(map_error(load(data, &LoadOptions::default())), MapError::NoLayers));
+    }
+
+    #[test]
+    fn absurd_layer_count_is_rejected() {
+        let layer = TestLayer { tile_size: (2, 2), map_size: (1, 1), cells: &[1] };
+        let mut data = raw_map(&[0, 0x7fff], &[layer], &[&[1, 1, 1, 1]]);
+        data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(map_error(load(data, &LoadOptions::default())), MapError::TooManyLayers(u32::MAX)));
+    }
+
+    #[test]
+    fn absurd_palette_size_is_rejected() {
+        let layer = TestLayer { tile_size: (2, 2), map_size: (1, 1), cells: &[1] };
+        let mut data = raw_map(&[0, 0x7fff], &[layer], &[&[1, 1, 1, 1]]);
+        // After the magic, base offset, unknown u32, layer count and one layer offset
+        data[20..24].copy_from_slice(&0x4000_0000u32.to_le_bytes());
+        assert!(matches!(
+            map_error(load(data, &LoadOptions::default())),
+            MapError::PaletteTooLarge(0x4000_0000)
+        ));
+    }
+
+    #[test]
+    fn decoding_is_the_same_on_any_number_of_threads() {
+        let palette: Vec<u16> = (0..256).map(|i| (i * 0x7f) as u16 & 0x7fff).collect();
+        let tiles: Vec<Vec<u8>> =
+            (0..64u32).map(|t| (0..64).map(|i| ((t * 31 + i * 7) % 256) as u8).collect()).collect();
+        let tiles: Vec<&[u8]> = tiles.iter().map(Vec::as_slice).collect();
+        let cells: Vec<u32> = (0..256).map(|i| i % 65).collect();
+        let layer = TestLayer { tile_size: (8, 8), map_size: (16, 16), cells: &cells };
+        let data = raw_map(&palette, &[layer], &tiles);
+
+        let load_on = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| load(data.clone(), &LoadOptions::default()).map_err(|e| e.to_string())).unwrap()
+        };
+        let sequential = load_on(1);
+        let parallel = load_on(8);
+
+        assert_eq!(sequential.layers[0].tiles.len(), 64);
+        assert_eq!(sequential.layers[0].tiles, parallel.layers[0].tiles);
+    }
+
+    #[test]
+    fn palette_top_bit_is_transparency_only_with_palette_alpha() {
+        // Index 1 is red with the top bit set, index 2 is plain green
+        let palette = [0, 0x8000 | 0x7c00, 0x03e0];
+        let layer = TestLayer { tile_size: (2, 1), map_size: (1, 1), cells: &[1] };
+        let data = raw_map(&palette, &[layer], &[&[1, 2]]);
+
+        let with_alpha = load(data.clone(), &LoadOptions { palette_alpha: true, ..LoadOptions::default() }).unwrap();
+        let tile = with_alpha.layers[0].tiles.values().next().unwrap();
+        assert_eq!(tile.pixels, [0xf8, 0, 0, 0, 0, 0xf8, 0, 0xff]);
+
+        let without = load(data, &LoadOptions::default()).unwrap();
+        let tile = without.layers[0].tiles.values().next().unwrap();
+        assert_eq!(tile.pixels, [0xf8, 0, 0, 0xff, 0, 0xf8, 0, 0xff]);
+    }
+
+    #[test]
+    fn layers_read_a_shared_tile_at_their_own_tile_size() {
+        // Both layers point at the same tile id, the first reads it as 2x2 and the second as 4x4
+        let palette: Vec<u16> = (0..17).map(|i| i * 0x0421).collect();
+        let indices: Vec<u8> = (1..=16).collect();
+        let layers = [
+            TestLayer { tile_size: (2, 2), map_size: (4, 4), cells: &[1; 16] },
+            TestLayer { tile_size: (4, 4), map_size: (1, 1), cells: &[1] },
+        ];
+        let map = load(raw_map(&palette, &layers, &[&indices]), &LoadOptions::default()).unwrap();
+
+        let tile_id = map.layers[0].tile_map[0];
+        assert_eq!(map.layers[1].tile_map, [tile_id]);
+        assert_eq!(map.layers[0].tiles[&tile_id].indices, [1, 2, 3, 4]);
+        assert_eq!(map.layers[1].tiles[&tile_id].indices, indices);
+        assert_eq!(map.layers[0].pixel_size(), Dimensions::new(8, 8));
+        assert_eq!(map.layers[1].pixel_size(), Dimensions::new(4, 4));
+        assert_eq!(map.pixel_size(), Dimensions::new(8, 8));
+        assert_eq!(map.tile_spans(), [(tile_id, 4), (tile_id, 16)]);
+
+        // The second layer is drawn over the top left of the first at its own size
+        let image = crate::render::render_map(&map, &Default::default()).unwrap();
+        let colour = |index: usize| {
+            let colour = map.palette[index];
+            [colour.r, colour.g, colour.b, colour.a]
+        };
+        assert_eq!(image.dimensions(), (8, 8));
+        assert_eq!(image.get_pixel(3, 3).0, colour(16));
+        assert_eq!(image.get_pixel(3, 0).0, colour(4));
+        assert_eq!(image.get_pixel(5, 5).0, colour(4));
+        assert_eq!(image.get_pixel(6, 4).0, colour(1));
+    }
+
+    #[test]
+    fn duplicate_tiles_in_two_layers_are_merged() {
+        let palette = [0, 0x7fff];
+        let layers = [
+            TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[1, 2] },
+            TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[2, 3] },
+        ];
+        let tile: &[u8] = &[1, 0, 0, 1];
+        let mut map = load(raw_map(&palette, &layers, &[tile, tile, tile]), &LoadOptions::default()).unwrap();
+        let first_id = map.layers[0].tile_map[0];
+
+        assert_eq!(map.deduplicate_tiles(), 2);
+        for layer in &map.layers {
+            assert_eq!(layer.tile_map, [first_id, first_id]);
+            assert_eq!(layer.tiles.keys().copied().collect::<Vec<_>>(), [first_id]);
+            assert_eq!(layer.tiles[&first_id].indices, tile);
+        }
+    }
+
+    #[test]
+    fn same_indices_at_different_tile_sizes_are_not_merged() {
+        let palette = [0, 0x7fff];
+        let layers = [
+            TestLayer { tile_size: (2, 2), map_size: (1, 1), cells: &[1] },
+            TestLayer { tile_size: (4, 1), map_size: (1, 1), cells: &[2] },
+        ];
+        let tile: &[u8] = &[1, 1, 1, 1];
+        let mut map = load(raw_map(&palette, &layers, &[tile, tile]), &LoadOptions::default()).unwrap();
+        let tile_maps: Vec<Vec<u32>> = map.layers.iter().map(|layer| layer.tile_map.clone()).collect();
+
+        assert_eq!(map.deduplicate_tiles(), 0);
+        for (layer, tile_map) in map.layers.iter().zip(&tile_maps) {
+            assert_eq!(&layer.tile_map, tile_map);
+            assert!(layer.tiles.contains_key(&tile_map[0]));
+        }
+    }
+
+    #[test]
+    fn merged_cells_point_at_the_lowest_id_and_keep_their_place() {
+        // Tiles 1 and 3 are the same, 2 differs
+        let palette = [0, 0x7fff, 0x001f];
+        let layers = [TestLayer { tile_size: (2, 1), map_size: (5, 1), cells: &[2, 3, 1, 0, 2] }];
+        let tiles: [&[u8]; 3] = [&[1, 2], &[2, 1], &[1, 2]];
+        let mut map = load(raw_map(&palette, &layers, &tiles), &LoadOptions::default()).unwrap();
+        let layer = &map.layers[0];
+        let (lowest, other, highest) = (layer.tile_map[2], layer.tile_map[0], layer.tile_map[1]);
+        assert!(lowest < highest);
+
+        assert_eq!(map.deduplicate_tiles(), 1);
+        let layer = &map.layers[0];
+        assert_eq!(layer.tile_map, [other, lowest, lowest, 0, other]);
+        assert!(!layer.tiles.contains_key(&highest));
+        assert_eq!(layer.tiles[&lowest].indices, [1, 2]);
+        assert_eq!(layer.tiles[&other].indices, [2, 1]);
+    }
+
+    #[test]
+    fn one_layer_map_only_yields_tiles_from_its_layer() {
+        // The viewer preloads images from iter_tiles, which must not look for a second layer
+        let layers = [TestLayer { tile_size: (2, 2), map_size: (2, 2), cells: &[1, 2, 0, 1] }];
+        let map = load(raw_map(&[0, 0x7fff], &layers, &[&[1; 4], &[0, 1, 1, 0]]), &LoadOptions::default()).unwrap();
+
+        assert_eq!(map.layers.len(), 1);
+        let mut tiles: Vec<(usize, u32)> = map.iter_tiles().map(|(l, tile_id, _)| (l, tile_id)).collect();
+        tiles.sort();
+        let tile_map = &map.layers[0].tile_map;
+        assert_eq!(tiles, [(0, tile_map[0]), (0, tile_map[1])]);
+        assert_eq!(map.pixel_size(), Dimensions::new(4, 4));
+        assert_eq!(map.content_bounds(), Some(((0, 0), (4, 4))));
+    }
+
+    #[test]
+    fn tile_painted_from_another_layer_gets_an_image() {
+        let layers = [
+            TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[1, 0] },
+            TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[0, 2] },
+            TestLayer { tile_size: (4, 2), map_size: (1, 1), cells: &[3] },
+        ];
+        let tiles: [&[u8]; 3] = [&[1; 4], &[0, 1, 1, 0], &[1; 8]];
+        let mut map = load(raw_map(&[0, 0x7fff], &layers, &tiles), &LoadOptions::default()).unwrap();
+        let (own, other, wide) = (map.layers[0].tile_map[0], map.layers[1].tile_map[1], map.layers[2].tile_map[0]);
+
+        // The viewer creates an image for every (layer, tile id) from iter_tiles
+        assert_eq!(map.paint_tile(0, 1, 0, other), Some(0));
+        let images: Vec<(usize, u32)> = map.iter_tiles().map(|(l, tile_id, _)| (l, tile_id)).collect();
+        assert!(images.contains(&(0, other)));
+        assert_eq!(map.layers[0].tiles[&other].indices, map.layers[1].tiles[&other].indices);
+
+        // Painting over a cell with the tile it holds, or with a tile that wouldn't fit, does nothing
+        assert_eq!(map.paint_tile(0, 1, 0, other), None);
+        assert_eq!(map.paint_tile(0, 0, 0, wide), None);
+        assert_eq!(map.layers[0].tile_map, [own, other]);
+        assert!(!map.layers[0].tiles.contains_key(&wide));
+    }
+
+    #[test]
+    fn palette_offset_only_shifts_its_own_layer() {
+        let palette = [0, 0x7c00, 0x03e0];
+        let layers = [
+            TestLayer { tile_size: (1, 1), map_size: (1, 1), cells: &[1] },
+            TestLayer { tile_size: (1, 1), map_size: (1, 1), cells: &[1] },
+        ];
+        let mut map = load(raw_map(&palette, &layers, &[&[1]]), &LoadOptions::default()).unwrap();
+        let tile_id = map.layers[0].tile_map[0];
+
+        map.layers[1].palette_offset = 1;
+        let embedded = map.palette.clone();
+        map.redecode(&embedded, &DecodeOptions::default());
+
+        // The tile is shared, but each layer keeps its own decoding of it
+        assert_eq!(map.layers[0].tiles[&tile_id].pixels, [0xf8, 0, 0, 0xff]);
+        assert_eq!(map.layers[1].tiles[&tile_id].pixels, [0, 0xf8, 0, 0xff]);
+    }
+
+    // Two layers with their own tile sizes, empty cells and a tile used more than once
+    fn sample_raw_map() -> Vec<u8> {
+        let palette = [0, 0x7c00, 0x03e0, 0x001f, 0x7fff];
+        let layers = [
+            TestLayer { tile_size: (2, 2), map_size: (3, 2), cells: &[1, 2, 1, 0, 2, 3] },
+            TestLayer { tile_size: (4, 2), map_size: (2, 1), cells: &[0, 4] },
+        ];
+        let tiles: [&[u8]; 4] = [&[1, 2, 3, 4], &[4, 3, 2, 1], &[0, 1, 0, 1], &[1, 1, 2, 2, 3, 3, 4, 4]];
+        raw_map(&palette, &layers, &tiles)
+    }
+
+    fn sample_map() -> Map {
+        load(sample_raw_map(), &LoadOptions::default()).unwrap()
+    }
+
+    fn assert_same_map(actual: &Map, expected: &Map) {
+        assert_eq!(actual.palette, expected.palette);
+        assert_eq!(actual.layers.len(), expected.layers.len());
+        for (actual, expected) in actual.layers.iter().zip(&expected.layers) {
+            assert_eq!(actual.map_size, expected.map_size);
+            assert_eq!(actual.tile_size, expected.tile_size);
+            assert_eq!(actual.tile_map, expected.tile_map);
+            assert_eq!(actual.tiles, expected.tiles);
+            assert!(actual.failed_tiles.is_empty());
+        }
+    }
+
+    #[test]
+    fn saved_map_loads_back_the_same() {
+        let map = sample_map();
+        let path = std::env::temp_dir().join(format!("kknd2-mapview-save-{}.MAPD", std::process::id()));
+        save_map(&path, &map, &map.palette).unwrap();
+        let loaded = load_map(&path, &LoadOptions::default());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_same_map(&loaded.unwrap(), &map);
+    }
+
+    #[test]
+    fn written_map_parses_back_the_same() {
+        let map = sample_map();
+        let mut data = Vec::<u8>::new();
+        write_map(&mut data, &map, &map.palette).unwrap();
+        let parsed = load(data, &LoadOptions::default()).unwrap();
+
+        assert_same_map(&parsed, &map);
+        let layer = &parsed.layers[0];
+        assert_eq!(layer.map_size, Dimensions::new(3, 2));
+        assert_eq!(layer.tile_size, Dimensions::new(2, 2));
+        assert_eq!(layer.tiles.len(), 3);
+        assert_eq!(
+            layer.tiles[&layer.tile_map[0]].pixels,
+            [0xf8, 0, 0, 0xff, 0, 0xf8, 0, 0xff, 0, 0, 0xf8, 0xff, 0xf8, 0xf8, 0xf8, 0xff]
+        );
+        let layer = &parsed.layers[1];
+        assert_eq!(layer.tile_size, Dimensions::new(4, 2));
+        assert_eq!(layer.tile_map[0], 0);
+        assert_eq!(layer.tiles[&layer.tile_map[1]].indices, [1, 1, 2, 2, 3, 3, 4, 4]);
+    }
+
+    #[test]
+    fn written_palette_replaces_the_embedded_one() {
+        let map = sample_map();
+        let mut palette = map.palette.clone();
+        palette[1] = Colour::opaque(0x08, 0x10, 0x18);
+        let mut data = Vec::<u8>::new();
+        write_map(&mut data, &map, &palette).unwrap();
+        let parsed = load(data, &LoadOptions::default()).unwrap();
+
+        assert_eq!(parsed.palette, palette);
+        let layer = &parsed.layers[0];
+        assert_eq!(layer.tile_map, map.layers[0].tile_map);
+        assert_eq!(layer.tiles[&layer.tile_map[0]].pixels[..4], [0x08, 0x10, 0x18, 0xff]);
+    }
+
+    fn layer_with(map_size: Dimensions, tile_map: Vec<u32>) -> MapLayer {
+        MapLayer {
+            map_size,
+            tile_size: Dimensions::new(2, 2),
+            tile_map,
+            tiles: HashMap::new(),
+            failed_tiles: HashMap::new(),
+            palette_offset: 0,
+            data_offset: 0,
+            data_len: 0,
+        }
+    }
+
+    #[test]
+    fn tile_at_covers_exactly_the_map() {
+        let layer = layer_with(Dimensions::new(3, 2), vec![10, 20, 30, 40, 50, 60]);
+        assert_eq!(layer.tile_at(0, 0), Some(10));
+        assert_eq!(layer.tile_at(2, 0), Some(30));
+        assert_eq!(layer.tile_at(0, 1), Some(40));
+        assert_eq!(layer.tile_at(2, 1), Some(60));
+        assert_eq!(layer.tile_at(3, 0), None);
+        assert_eq!(layer.tile_at(0, 2), None);
+        assert_eq!(layer.tile_at(3, 2), None);
+        assert_eq!(layer.tile_at(u32::MAX, u32::MAX), None);
+    }
+
+    #[test]
+    fn tile_at_is_none_past_a_short_tile_map() {
+        // The map claims 3x2 cells but only 4 are stored
+        let layer = layer_with(Dimensions::new(3, 2), vec![10, 20, 30, 40]);
+        assert_eq!(layer.tile_at(0, 1), Some(40));
+        assert_eq!(layer.tile_at(1, 1), None);
+        assert_eq!(layer.tile_at(2, 1), None);
+    }
+
+    #[test]
+    fn data_position_accepts_only_offsets_inside_the_data() {
+        // With the data taken from offset 0x100 of its archive, stored offset 0x100 is
+        // the magic at position 0 and the data is 0x40 bytes long
+        assert_eq!(data_position(0xf8, 0x100, 0x40).unwrap(), 0);
+        assert_eq!(data_position(0x137, 0x100, 0x40).unwrap(), 0x3f);
+        assert!(matches!(data_position(0xf7, 0x100, 0x40), Err(MapError::InvalidOffset(0xf7))));
+        assert!(matches!(data_position(0x138, 0x100, 0x40), Err(MapError::InvalidOffset(0x138))));
+        assert!(matches!(data_position(u32::MAX, 0, 0x40), Err(MapError::InvalidOffset(u32::MAX))));
+    }
+
+    #[test]
+    fn tile_id_past_the_end_of_the_data_is_rejected() {
+        let layer = TestLayer { tile_size: (2, 2), map_size: (1, 1), cells: &[1] };
+        let mut data = raw_map(&[0, 0x7fff], &[layer], &[&[1; 4]]);
+        // The only cell, after the 28 byte header and the 32 byte layer header
+        data[60..64].copy_from_slice(&0x0010_0000u32.to_le_bytes());
+        assert!(matches!(
+            map_error(load(data, &LoadOptions::default())),
+            MapError::InvalidOffset(0x0010_0000)
+        ));
+    }
+
+    #[test]
+    fn layer_offset_before_the_map_data_is_rejected() {
+        let layer = TestLayer { tile_size: (2, 2), map_size: (1, 1), cells: &[1] };
+        let mut data = raw_map(&[0, 0x7fff], &[layer], &[&[1; 4]]);
+        let layer_offset = u32::from_le_bytes(data[16..20].try_into().unwrap());
+        // Claim the data came from much further into its archive than the stored offsets
+        data[4..8].copy_from_slice(&0x1_0000u32.to_le_bytes());
+        assert!(matches!(
+            map_error(load(data, &LoadOptions::default())),
+            MapError::InvalidOffset(offset) if offset == layer_offset
+        ));
+    }
+
+    // Two 2x2 tiles with the second cut two bytes short by the end of the data
+    fn map_with_truncated_tile() -> Vec<u8> {
+        let layer = TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[1, 2] };
+        let mut data = raw_map(&[0, 0x7fff], &[layer], &[&[1; 4], &[1; 4]]);
+        data.truncate(data.len() - 2);
+        data
+    }
+
+    #[test]
+    fn truncated_tile_is_rejected_by_default() {
+        assert!(matches!(
+            map_error(load(map_with_truncated_tile(), &LoadOptions::default())),
+            MapError::TruncatedTile { expected: 4, available: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn allow_truncated_loads_a_truncated_tile_as_failed() {
+        let options = LoadOptions { allow_truncated: true, ..LoadOptions::default() };
+        let map = load(map_with_truncated_tile(), &options).unwrap();
+        let layer = &map.layers[0];
+        let (whole, truncated) = (layer.tile_map[0], layer.tile_map[1]);
+        assert!(layer.tiles.contains_key(&whole));
+        assert!(!layer.tiles.contains_key(&truncated));
+        assert!(matches!(layer.failed_tiles[&truncated], MapError::TruncatedTile { expected: 4, available: 2, .. }));
+        assert_eq!(layer.failed_tiles.len(), 1);
+    }
+
+    // Wraps raw MAPD data as the only chunk of a level archive, stored uncompressed. The
+    // archive starts with its table of contents offset and then a file table holding
+    // the chunk's offset, BASE_OFFSET, so the chunk's offsets mean the same in both
+    fn archive(raw: &[u8]) -> Vec<u8> {
+        let chunk = &raw[DATA_HEADER_SIZE as usize..];
+        let toc_offset = BASE_OFFSET + chunk.len() as u32;
+
+        let mut archive = Vec::<u8>::new();
+        archive.write_u32::<LittleEndian>(toc_offset).unwrap();
+        archive.write_u32::<LittleEndian>(BASE_OFFSET).unwrap();
+        archive.write_u32::<LittleEndian>(0).unwrap();
+        archive.extend_from_slice(chunk);
+        for value in [MAPD_KIND, 4, 0, 0] {
+            archive.write_u32::<LittleEndian>(value).unwrap();
+        }
+
+        // A compressed file whose archive part is one block stored as is, followed by an
+        // empty metadata part
+        let mut file = Vec::<u8>::new();
+        file.extend_from_slice(&[0; 8]);
+        file.write_u32::<byteorder::BigEndian>(archive.len() as u32).unwrap();
+        file.write_u32::<LittleEndian>(0).unwrap();
+        file.write_u32::<LittleEndian>(archive.len() as u32).unwrap();
+        file.write_u32::<LittleEndian>(archive.len() as u32).unwrap();
+        file.extend_from_slice(&archive);
+        file.write_u32::<LittleEndian>(0).unwrap();
+        file.write_u32::<LittleEndian>(0).unwrap();
+        file
+    }
+
+    #[test]
+    fn raw_and_archived_map_data_load_the_same() {
+        let raw = sample_raw_map();
+        let from_raw = load(raw.clone(), &LoadOptions::default()).unwrap();
+        let from_archive = load(archive(&raw), &LoadOptions::default()).unwrap();
+
+        assert_eq!(from_archive.palette, from_raw.palette);
+        assert_eq!(from_archive.layers.len(), 2);
+        for (archived, raw) in from_archive.layers.iter().zip(&from_raw.layers) {
+            assert_eq!(archived.tile_map, raw.tile_map);
+            assert_eq!(archived.data_offset, raw.data_offset);
+            let mut ids: Vec<u32> = archived.tiles.keys().copied().collect();
+            ids.sort_unstable();
+            let mut raw_ids: Vec<u32> = raw.tiles.keys().copied().collect();
+            raw_ids.sort_unstable();
+            assert_eq!(ids, raw_ids);
+            for (tile_id, tile) in &archived.tiles {
+                assert_eq!(tile.indices, raw.tiles[tile_id].indices);
             }
         }
+        assert_eq!(from_raw.layers[1].tiles[&from_raw.layers[1].tile_map[1]].indices, [1, 1, 2, 2, 3, 3, 4, 4]);
     }
 }
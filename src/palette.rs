@@ -0,0 +1,164 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use log::warn;
+
+// Adobe Color Tables are 256 RGB triples, optionally followed by a
+// big-endian colour count and transparent index
+const ACT_COLOURS: usize = 256;
+const ACT_SIZE: usize = ACT_COLOURS * 3;
+const ACT_EXTENDED_SIZE: usize = ACT_SIZE + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Colour {
+    // Map palettes store colours as 15-bit RGB (xRRRRRGG GGGBBBBB)
+    pub fn from_packed(colour_packed: u16) -> Colour {
+        Colour {
+            r: (((colour_packed & 0x7c00) >> 7) & 0xff) as u8,
+            g: (((colour_packed & 0x03e0) >> 2) & 0xff) as u8,
+            b: (((colour_packed & 0x001f) << 3) & 0xff) as u8,
+            a: 0xff,
+        }
+    }
+
+    // Some 15-bit formats use the otherwise unused top bit to mark a colour as transparent
+    pub fn from_packed_with_alpha(colour_packed: u16) -> Colour {
+        let alpha = if colour_packed & 0x8000 != 0 { 0 } else { 0xff };
+        Colour { a: alpha, ..Colour::from_packed(colour_packed) }
+    }
+
+    // The inverse of from_packed_with_alpha, dropping the low 3 bits of each channel
+    pub fn to_packed(&self) -> u16 {
+        let alpha = if self.a == 0 { 0x8000 } else { 0 };
+        alpha | ((self.r as u16 >> 3) << 10) | ((self.g as u16 >> 3) << 5) | (self.b as u16 >> 3)
+    }
+
+    pub fn opaque(r: u8, g: u8, b: u8) -> Colour {
+        Colour { r, g, b, a: 0xff }
+    }
+
+    // Perceived brightness, weighted as in Rec. 601
+    pub fn luminance(&self) -> u8 {
+        ((self.r as u32 * 299 + self.g as u32 * 587 + self.b as u32 * 114) / 1000) as u8
+    }
+}
+
+fn parse_act(data: &[u8]) -> Result<Vec<Colour>, Box<dyn Error>> {
+    let count = if data.len() == ACT_EXTENDED_SIZE {
+        let count = u16::from_be_bytes(data[ACT_SIZE..ACT_SIZE + 2].try_into()?) as usize;
+        if count == 0 || count > ACT_COLOURS {
+            ACT_COLOURS
+        } else {
+            count
+        }
+    } else {
+        data.len() / 3
+    };
+
+    Ok(data
+        .chunks_exact(3)
+        .take(count)
+        .map(|rgb| Colour::opaque(rgb[0], rgb[1], rgb[2]))
+        .collect())
+}
+
+fn parse_jasc_pal(text: &str) -> Result<Vec<Colour>, Box<dyn Error>> {
+    let mut lines = text.lines().map(str::trim);
+
+    if lines.next() != Some("JASC-PAL") {
+        return Err("Invalid JASC palette: missing header".into());
+    }
+
+    // Skip the version line
+    lines.next();
+
+    let count: usize = lines
+        .next()
+        .ok_or("Invalid JASC palette: missing colour count")?
+        .parse()
+        .map_err(|e| format!("Invalid JASC palette colour count: {}", e))?;
+
+    let mut palette = Vec::<Colour>::with_capacity(count);
+    for line in lines.filter(|line| !line.is_empty()).take(count) {
+        let components: Vec<u8> = line
+            .split_whitespace()
+            .map(|c| c.parse::<u8>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Invalid JASC palette entry {:?}: {}", line, e))?;
+
+        if components.len() < 3 {
+            return Err(format!("Invalid JASC palette entry {:?}", line).into());
+        }
+
+        palette.push(Colour::opaque(components[0], components[1], components[2]));
+    }
+
+    Ok(palette)
+}
+
+// Both formats hold exactly ACT_COLOURS entries, so shorter palettes are padded
+// with black and longer ones truncated
+fn fixed_size_palette(palette: &[Colour]) -> Vec<Colour> {
+    if palette.len() != ACT_COLOURS {
+        warn!(
+            "Palette has {} colours, exporting {}",
+            palette.len(),
+            ACT_COLOURS
+        );
+    }
+
+    let mut colours: Vec<Colour> = palette.iter().copied().take(ACT_COLOURS).collect();
+    colours.resize(ACT_COLOURS, Colour::opaque(0, 0, 0));
+    colours
+}
+
+fn format_act(palette: &[Colour]) -> Vec<u8> {
+    palette.iter().flat_map(|colour| [colour.r, colour.g, colour.b]).collect()
+}
+
+fn format_jasc_pal(palette: &[Colour]) -> String {
+    let mut text = format!("JASC-PAL\r\n0100\r\n{}\r\n", palette.len());
+    for colour in palette {
+        text.push_str(&format!("{} {} {}\r\n", colour.r, colour.g, colour.b));
+    }
+    text
+}
+
+// Writes a JASC palette for .pal files and an Adobe Color Table otherwise
+pub fn save_palette(path: &Path, palette: &[Colour]) -> Result<(), Box<dyn Error>> {
+    let palette = fixed_size_palette(palette);
+    let is_jasc = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pal"));
+
+    let data = if is_jasc {
+        format_jasc_pal(&palette).into_bytes()
+    } else {
+        format_act(&palette)
+    };
+
+    fs::write(path, data).map_err(|e| format!("Failed to write palette {:?}: {}", path, e).into())
+}
+
+pub fn load_palette(path: &Path) -> Result<Vec<Colour>, Box<dyn Error>> {
+    let data = fs::read(path).map_err(|e| format!("Failed to open palette {:?}: {}", path, e))?;
+
+    if data.starts_with(b"JASC-PAL") {
+        parse_jasc_pal(&String::from_utf8_lossy(&data))
+    } else {
+        parse_act(&data)
+    }
+}
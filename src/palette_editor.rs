@@ -0,0 +1,108 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use speedy2d::color::Color;
+use speedy2d::dimen::Vec2;
+use speedy2d::shape::Rectangle;
+use speedy2d::Graphics2D;
+
+use kknd2_mapview::palette::Colour;
+
+use crate::browser::draw_outline;
+
+const COLUMNS: usize = 16;
+const SWATCH_SIZE: f32 = 16.0;
+const MARGIN: f32 = 16.0;
+// Matches the 5 bits per channel the map palettes are stored with
+const CHANNEL_STEP: i32 = 8;
+
+#[derive(Clone, Copy)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+// Grid of palette swatches drawn over the map, with a selection cursor. The colours
+// themselves live on MapView, so edits outlast the editor being closed
+pub struct PaletteEditor {
+    selection: usize,
+    len: usize,
+}
+
+impl PaletteEditor {
+    pub fn new(palette: &[Colour]) -> PaletteEditor {
+        PaletteEditor { selection: 0, len: palette.len() }
+    }
+
+    pub fn selection(&self) -> usize {
+        self.selection
+    }
+
+    pub fn move_selection(&mut self, dx: isize, dy: isize) {
+        if self.len == 0 {
+            return;
+        }
+
+        let selection = self.selection as isize + dx + dy * COLUMNS as isize;
+        self.selection = selection.clamp(0, self.len as isize - 1) as usize;
+    }
+
+    // Returns the palette index of the swatch under a screen position
+    pub fn hit_test(&self, position: Vec2, scale_factor: f32) -> Option<usize> {
+        let relative = (position - Vec2::new(MARGIN, MARGIN) * scale_factor) / (SWATCH_SIZE * scale_factor);
+        if relative.x < 0.0 || relative.y < 0.0 || relative.x >= COLUMNS as f32 {
+            return None;
+        }
+
+        let index = relative.y as usize * COLUMNS + relative.x as usize;
+        (index < self.len).then_some(index)
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if index < self.len {
+            self.selection = index;
+        }
+    }
+
+    // Position just below the grid, where the selected colour is described
+    pub fn label_position(&self, scale_factor: f32) -> Vec2 {
+        let rows = self.len.div_ceil(COLUMNS);
+        Vec2::new(MARGIN, MARGIN + rows as f32 * SWATCH_SIZE + 4.0) * scale_factor
+    }
+
+    pub fn draw(&self, graphics: &mut Graphics2D, palette: &[Colour], scale_factor: f32) {
+        let size = SWATCH_SIZE * scale_factor;
+        let top_left = Vec2::new(MARGIN, MARGIN) * scale_factor;
+        let rows = self.len.div_ceil(COLUMNS);
+
+        graphics.draw_rectangle(
+            Rectangle::new(
+                top_left - Vec2::new(4.0, 4.0),
+                top_left + Vec2::new(COLUMNS as f32 * size, rows as f32 * size + 32.0 * scale_factor),
+            ),
+            Color::from_rgba(0.0, 0.0, 0.0, 0.85),
+        );
+
+        for (index, colour) in palette.iter().enumerate().take(self.len) {
+            let cell = top_left + Vec2::new((index % COLUMNS) as f32, (index / COLUMNS) as f32) * size;
+            let colour = Color::from_int_rgb(colour.r, colour.g, colour.b);
+            graphics.draw_rectangle(Rectangle::new(cell, cell + Vec2::new(size, size)), colour);
+        }
+
+        let cell = top_left + Vec2::new((self.selection % COLUMNS) as f32, (self.selection / COLUMNS) as f32) * size;
+        draw_outline(graphics, cell, cell + Vec2::new(size, size), Color::YELLOW);
+    }
+}
+
+// Moves one channel of a colour by a number of steps, stopping at black and full intensity
+pub fn adjust_channel(colour: &mut Colour, channel: Channel, steps: i32) {
+    let value = match channel {
+        Channel::Red => &mut colour.r,
+        Channel::Green => &mut colour.g,
+        Channel::Blue => &mut colour.b,
+    };
+    *value = (*value as i32 + steps * CHANNEL_STEP).clamp(0, 255) as u8;
+}
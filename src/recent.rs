@@ -0,0 +1,94 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+const MAX_RECENT_FILES: usize = 10;
+
+fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir).join("kknd2-mapview"));
+    }
+
+    if let Some(dir) = env::var_os("APPDATA") {
+        return Some(PathBuf::from(dir).join("kknd2-mapview"));
+    }
+
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("kknd2-mapview"))
+}
+
+fn recent_files_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("recent"))
+}
+
+// Paths are stored as raw bytes on Unix so non UTF-8 names survive the round trip
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// Most recently loaded first
+pub fn recent_files() -> Vec<PathBuf> {
+    let contents = match recent_files_path().and_then(|path| fs::read(path).ok()) {
+        None => return Vec::new(),
+        Some(contents) => contents,
+    };
+
+    contents
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+pub fn add_recent_file(path: &Path) {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    let mut files = recent_files();
+    files.retain(|file| *file != path);
+    files.insert(0, path);
+    files.truncate(MAX_RECENT_FILES);
+
+    let recent_path = match recent_files_path() {
+        None => return,
+        Some(recent_path) => recent_path,
+    };
+
+    let mut contents = Vec::<u8>::new();
+    for file in &files {
+        contents.extend(path_to_bytes(file));
+        contents.push(b'\n');
+    }
+
+    // Failing to remember a file shouldn't get in the way of viewing it
+    let result = recent_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&recent_path, contents));
+    if let Err(e) = result {
+        warn!("Failed to save recent files to {}: {}", recent_path.display(), e);
+    }
+}
@@ -0,0 +1,70 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::error::Error;
+
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+use crate::map::Map;
+
+#[derive(Clone, Copy, Default)]
+pub struct RenderOptions {
+    // Solid colour drawn behind the map, for an opaque image without an alpha channel
+    pub background: Option<(u8, u8, u8)>,
+}
+
+// Composites every layer of the map into a single image, without a window
+pub fn render_map(map: &Map, options: &RenderOptions) -> Result<RgbaImage, Box<dyn Error>> {
+    if map.layers.is_empty() {
+        return Err("Map has no layers".into());
+    }
+    let size = map.pixel_size();
+    let (width, height) = (size.width, size.height);
+
+    let mut image = match options.background {
+        None => RgbaImage::new(width, height),
+        Some((r, g, b)) => RgbaImage::from_pixel(width, height, image::Rgba([r, g, b, 0xff])),
+    };
+
+    for layer in &map.layers {
+        for (position, tile_index) in layer.tile_map.iter().enumerate() {
+            let tile = match layer.tiles.get(tile_index) {
+                None => continue,
+                Some(tile) => tile,
+            };
+
+            let (column, row) = layer.map_size.position_of(position);
+            let tile_x = column * layer.tile_size.width;
+            let tile_y = row * layer.tile_size.height;
+
+            for (i, pixel) in tile.pixels.chunks_exact(4).enumerate() {
+                // Palette index 0 is transparent, let the lower layers or background show through
+                if pixel[3] == 0 {
+                    continue;
+                }
+
+                let (pixel_x, pixel_y) = layer.tile_size.position_of(i);
+                let (x, y) = (tile_x + pixel_x, tile_y + pixel_y);
+                if size.contains(x, y) {
+                    image.get_pixel_mut(x, y).0.copy_from_slice(pixel);
+                }
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+pub fn render_thumbnail(map: &Map, width: u32, options: &RenderOptions) -> Result<RgbaImage, Box<dyn Error>> {
+    let image = render_map(map, options)?;
+    if image.width() == 0 || image.height() == 0 {
+        return Err("Map has no visible area".into());
+    }
+
+    let height = ((image.height() as u64 * width as u64) / image.width() as u64).max(1) as u32;
+
+    Ok(imageops::resize(&image, width, height, FilterType::Triangle))
+}
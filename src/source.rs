@@ -0,0 +1,68 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::Path;
+
+use zip::ZipArchive;
+
+use crate::map::{is_supported_extension, load_map_from_reader, LoadOptions, Map, SUPPORTED_EXTENSIONS};
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Somewhere a map file can be read from. Either raw MAPD data or a level archive is
+// accepted, the same as from a file. Files and zip entries are built in, other backends
+// such as a download or a virtual file system implement this to reuse the parser
+pub trait MapSource {
+    fn open(&self) -> Result<Box<dyn ReadSeek>, Box<dyn Error>>;
+}
+
+impl MapSource for Path {
+    fn open(&self) -> Result<Box<dyn ReadSeek>, Box<dyn Error>> {
+        let file = File::open(self).map_err(|e| format!("Failed to open file: {}", e))?;
+        Ok(Box::new(file))
+    }
+}
+
+// An entry inside a zip file, e.g. a distribution of level archives
+pub struct ZipEntry<'a> {
+    pub archive: &'a Path,
+    pub entry: &'a str,
+}
+
+impl MapSource for ZipEntry<'_> {
+    // Zip entries can't seek, so the entry is read into memory
+    fn open(&self) -> Result<Box<dyn ReadSeek>, Box<dyn Error>> {
+        if !is_supported_extension(Path::new(self.entry)) {
+            return Err(format!(
+                "{} is not a recognized map, expected one of: {}",
+                self.entry,
+                SUPPORTED_EXTENSIONS.join(", ")
+            )
+            .into());
+        }
+
+        let file = File::open(self.archive).map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut archive = ZipArchive::new(BufReader::new(file))
+            .map_err(|e| format!("Failed to read zip {:?}: {}", self.archive, e))?;
+
+        let mut entry = archive
+            .by_name(self.entry)
+            .map_err(|e| format!("Failed to find {} in {:?}: {}", self.entry, self.archive, e))?;
+
+        let mut data = Vec::<u8>::new();
+        entry.read_to_end(&mut data)?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+}
+
+pub fn load_map_from<S: MapSource + ?Sized>(source: &S, options: &LoadOptions) -> Result<Map, Box<dyn Error>> {
+    let mut reader = BufReader::new(source.open()?);
+    load_map_from_reader(&mut reader, options)
+}
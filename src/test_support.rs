@@ -0,0 +1,78 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+// Builds raw MAPD data for the tests, benchmarks and fuzz targets, so the layout is
+// only written down once outside the parser. Not part of the library's API
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::map::{DATA_HEADER_SIZE, LAYER_MAGIC, MAP_MAGIC};
+
+// Where the MAPD data starts in its pretend level archive. Not the size of the header,
+// so tests notice if stored offsets are used as positions in the data
+pub const BASE_OFFSET: u32 = 12;
+
+// A layer for raw_map. Cells hold 0 for empty or n for the nth entry in tiles,
+// counted from 1, and are written as that tile's id
+pub struct TestLayer<'a> {
+    pub tile_size: (u32, u32),
+    pub map_size: (u32, u32),
+    pub cells: &'a [u32],
+}
+
+// Raw MAPD data laid out as the game does it: header, palette, layer blocks and
+// then the tiles, each tile taking as many bytes as its indices plus padding
+pub fn raw_map(palette: &[u16], layers: &[TestLayer], tiles: &[&[u8]]) -> Vec<u8> {
+    let header_size = 16 + layers.len() as u32 * 4 + 4 + palette.len() as u32 * 2;
+    let layers_size: u32 = layers.iter().map(|layer| 32 + layer.cells.len() as u32 * 4).sum();
+    let id_of = |position: u32| position - DATA_HEADER_SIZE + BASE_OFFSET;
+
+    // The low two bits of a tile id are flags, so tiles start on a multiple of 4
+    let mut tile_positions = Vec::<u32>::with_capacity(tiles.len());
+    let mut position = header_size + layers_size;
+    for tile in tiles {
+        position = position.next_multiple_of(4);
+        tile_positions.push(position);
+        position += tile.len() as u32;
+    }
+    let tile_ids: Vec<u32> = tile_positions.iter().map(|position| id_of(*position)).collect();
+
+    let mut data = Vec::<u8>::new();
+    data.write_u32::<LittleEndian>(MAP_MAGIC).unwrap();
+    data.write_u32::<LittleEndian>(BASE_OFFSET).unwrap();
+    data.write_u32::<LittleEndian>(0).unwrap();
+    data.write_u32::<LittleEndian>(layers.len() as u32).unwrap();
+    let mut position = header_size;
+    for layer in layers {
+        data.write_u32::<LittleEndian>(id_of(position)).unwrap();
+        position += 32 + layer.cells.len() as u32 * 4;
+    }
+    data.write_u32::<LittleEndian>(palette.len() as u32).unwrap();
+    for colour in palette {
+        data.write_u16::<LittleEndian>(*colour).unwrap();
+    }
+
+    for layer in layers {
+        data.write_u32::<LittleEndian>(LAYER_MAGIC).unwrap();
+        data.write_u32::<LittleEndian>(layer.tile_size.0).unwrap();
+        data.write_u32::<LittleEndian>(layer.tile_size.1).unwrap();
+        data.write_u32::<LittleEndian>(layer.map_size.0).unwrap();
+        data.write_u32::<LittleEndian>(layer.map_size.1).unwrap();
+        data.extend_from_slice(&[0; 12]);
+        for cell in layer.cells {
+            let tile_id = match cell {
+                0 => 0,
+                n => tile_ids[*n as usize - 1],
+            };
+            data.write_u32::<LittleEndian>(tile_id).unwrap();
+        }
+    }
+
+    for (tile, position) in tiles.iter().zip(&tile_positions) {
+        data.resize(*position as usize, 0);
+        data.extend_from_slice(tile);
+    }
+    data
+}
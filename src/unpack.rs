@@ -5,7 +5,14 @@
 
 use std::error::Error;
 use std::mem::size_of;
-use std::usize;
+
+// Renders a chunk kind as its four ASCII characters, e.g. 0x4450414d as "MAPD"
+pub fn fourcc_to_string(kind: u32) -> String {
+    kind.to_le_bytes()
+        .iter()
+        .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+        .collect()
+}
 
 struct TableEntry {
     pub kind: u32,
@@ -19,43 +26,48 @@ pub struct FileEntry {
     pub size: u32,
 }
 
-fn parse_table_of_contents_entry(data: &[u8]) -> Result<TableEntry, Box<dyn Error>> {
-    Ok(TableEntry {
-        kind: u32::from_le_bytes(data[0..4].try_into()?),
-        table_offset: u32::from_le_bytes(data[4..8].try_into()?),
-    })
+// Reads a u32 at an offset, or fails if it runs past the end of the archive
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+    let bytes = offset
+        .checked_add(4)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| format!("Offset {:#x} is past the end of the archive", offset))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
 }
 
-fn get_file_offset(data: &[u8], entry: u32) -> Result<u32, Box<dyn Error>> {
-    let entry_offset = (entry * 4) as usize;
-    Ok(u32::from_le_bytes(data[entry_offset..entry_offset + 4].try_into()?))
+fn parse_table_of_contents_entry(data: &[u8], offset: usize) -> Result<TableEntry, Box<dyn Error>> {
+    Ok(TableEntry {
+        kind: read_u32(data, offset)?,
+        table_offset: read_u32(data, offset + 4)?,
+    })
 }
 
-pub fn unpack(archive_data: &Vec<u8>) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+pub fn unpack(archive_data: &[u8]) -> Result<Vec<FileEntry>, Box<dyn Error>> {
     let mut files: Vec<FileEntry> = Vec::new();
 
-    let table_of_contents_offset = u32::from_le_bytes(archive_data[0..4].try_into()?);
-    let table_entry_size = size_of::<TableEntry>() as u32;
+    let table_of_contents_offset = read_u32(archive_data, 0)?;
+    let table_entry_size = size_of::<TableEntry>();
     for i in 0..7 {
-        let entry_offset = table_of_contents_offset + (i * table_entry_size);
-        let entry = parse_table_of_contents_entry(&archive_data[entry_offset as usize..])?;
+        let entry_offset = table_of_contents_offset as usize + (i * table_entry_size);
+        let entry = parse_table_of_contents_entry(archive_data, entry_offset)?;
 
         if entry.kind == 0 {
             break;
         }
 
-        let next_entry_offset = entry_offset + table_entry_size;
-        let next_entry = parse_table_of_contents_entry(&archive_data[next_entry_offset as usize..])?;
+        let next_entry = parse_table_of_contents_entry(archive_data, entry_offset + table_entry_size)?;
         let entry_end_offset = if next_entry.table_offset == 0 {
             table_of_contents_offset
         } else {
             next_entry.table_offset
         };
 
-        let file_table_size = entry_end_offset - entry.table_offset;
+        let file_table_size = entry_end_offset
+            .checked_sub(entry.table_offset)
+            .ok_or_else(|| format!("File table at {:#x} ends before it starts", entry.table_offset))?;
 
-        for j in 0..file_table_size / 4 {
-            let offset = get_file_offset(&archive_data[entry.table_offset as usize..], j as u32)?;
+        for j in 0..file_table_size as usize / 4 {
+            let offset = read_u32(archive_data, entry.table_offset as usize + j * 4)?;
             if offset == 0 {
                 break;
             }
@@ -65,19 +77,64 @@ pub fn unpack(archive_data: &Vec<u8>) -> Result<Vec<FileEntry>, Box<dyn Error>>
     }
 
     for i in 0..files.len() {
-        files[i].size = if i == files.len() - 1 {
-             table_of_contents_offset - files[i].offset
+        let end = if i == files.len() - 1 {
+            table_of_contents_offset
         } else {
-            files[i + 1].offset - files[i].offset
-        }
+            files[i + 1].offset
+        };
+        files[i].size = end
+            .checked_sub(files[i].offset)
+            .ok_or_else(|| format!("File at {:#x} ends before it starts", files[i].offset))?;
     }
 
     Ok(files)
 }
 
-pub fn extract_file(archive_data: &Vec<u8>, entry: &FileEntry) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn extract_file(archive_data: &[u8], entry: &FileEntry) -> Result<Vec<u8>, Box<dyn Error>> {
     let start  = entry.offset as usize;
     let end = start + entry.size as usize;
 
-    Ok(archive_data[start..end].to_vec())
+    match archive_data.get(start..end) {
+        Some(data) => Ok(data.to_vec()),
+        None => Err(format!("File at {:#x} runs past the end of the archive", entry.offset).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn archive_with_one_file_unpacks() {
+        // Table of contents at 16, after the file table at 4 and the file at 8
+        let data = archive(&[16, 8, 0, 0xabcd, 0x4450414d, 4, 0, 0]);
+        let files = unpack(&data).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!((files[0].kind, files[0].offset, files[0].size), (0x4450414d, 8, 8));
+        assert_eq!(extract_file(&data, &files[0]).unwrap(), archive(&[0, 0xabcd]));
+    }
+
+    #[test]
+    fn corrupt_archives_are_errors() {
+        let corrupt = [
+            // Too short for the table of contents offset
+            vec![1, 2],
+            // Table of contents past the end
+            archive(&[0x1000]),
+            // File table that starts after the table of contents
+            archive(&[8, 0, 0x4450414d, 0x20, 0, 0]),
+            // File past the table of contents
+            archive(&[16, 0x20, 0, 0, 0x4450414d, 4, 0, 0]),
+        ];
+        for data in corrupt {
+            assert!(unpack(&data).is_err(), "{:?} unpacked", data);
+        }
+
+        let entry = FileEntry { kind: 0x4450414d, offset: 4, size: 8 };
+        assert!(extract_file(&archive(&[0, 0]), &entry).is_err());
+    }
 }
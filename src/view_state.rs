@@ -0,0 +1,283 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::str::Chars;
+
+use crate::batch::json_string;
+
+// What the viewer was looking at, printed as one line of JSON for bug reports and
+// read back by --view
+pub struct ViewState {
+    pub file: Option<PathBuf>,
+    // Set instead of file for a map opened from a zip
+    pub zip: Option<(PathBuf, String)>,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub zoom: f32,
+    pub flip_h: bool,
+    pub flip_v: bool,
+    // Opacity of each layer, 0 for a hidden one
+    pub layer_opacity: Vec<f32>,
+}
+
+impl ViewState {
+    pub fn to_json(&self) -> String {
+        let mut fields = Vec::<String>::new();
+        if let Some(file) = &self.file {
+            fields.push(format!("\"file\": {}", json_string(&file.to_string_lossy())));
+        }
+        if let Some((archive, entry)) = &self.zip {
+            fields.push(format!("\"zip\": {}", json_string(&archive.to_string_lossy())));
+            fields.push(format!("\"entry\": {}", json_string(entry)));
+        }
+
+        let layers: Vec<String> = self.layer_opacity.iter().map(|opacity| format!("{}", opacity)).collect();
+        fields.push(format!("\"offset_x\": {}", self.offset_x));
+        fields.push(format!("\"offset_y\": {}", self.offset_y));
+        fields.push(format!("\"zoom\": {}", self.zoom));
+        fields.push(format!("\"flip_h\": {}", self.flip_h));
+        fields.push(format!("\"flip_v\": {}", self.flip_v));
+        fields.push(format!("\"layers\": [{}]", layers.join(", ")));
+        format!("{{{}}}", fields.join(", "))
+    }
+
+    // Fields that are left out keep their defaults, so a hand-written state only needs
+    // the parts that matter, e.g. {"zoom": 2}. Unknown fields are ignored
+    pub fn parse(json: &str) -> Result<ViewState, Box<dyn Error>> {
+        let mut fields = match Parser::new(json).parse_document()? {
+            Value::Object(fields) => fields,
+            _ => return Err("Expected a JSON object".into()),
+        };
+
+        let number = |fields: &mut HashMap<String, Value>, name: &str, default: f32| match fields.remove(name) {
+            None => Ok(default),
+            // Out of range values such as 1e999 would otherwise come through as infinity
+            Some(Value::Number(value)) if (value as f32).is_finite() => Ok(value as f32),
+            Some(_) => Err(format!("Expected a finite number for {}", name)),
+        };
+        let boolean = |fields: &mut HashMap<String, Value>, name: &str| match fields.remove(name) {
+            None => Ok(false),
+            Some(Value::Bool(value)) => Ok(value),
+            Some(_) => Err(format!("Expected true or false for {}", name)),
+        };
+        let string = |fields: &mut HashMap<String, Value>, name: &str| match fields.remove(name) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(format!("Expected a string for {}", name)),
+        };
+
+        let layer_opacity = match fields.remove("layers") {
+            None => Vec::new(),
+            Some(Value::Array(values)) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Number(opacity) => Ok((opacity as f32).clamp(0.0, 1.0)),
+                    _ => Err("Expected a list of layer opacities for layers"),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("Expected a list of layer opacities for layers".into()),
+        };
+
+        let zip = match (string(&mut fields, "zip")?, string(&mut fields, "entry")?) {
+            (Some(archive), Some(entry)) => Some((PathBuf::from(archive), entry)),
+            (None, None) => None,
+            _ => return Err("zip and entry must be given together".into()),
+        };
+
+        Ok(ViewState {
+            file: string(&mut fields, "file")?.map(PathBuf::from),
+            zip,
+            offset_x: number(&mut fields, "offset_x", 0.0)?,
+            offset_y: number(&mut fields, "offset_y", 0.0)?,
+            zoom: number(&mut fields, "zoom", 1.0)?,
+            flip_h: boolean(&mut fields, "flip_h")?,
+            flip_v: boolean(&mut fields, "flip_v")?,
+            layer_opacity,
+        })
+    }
+}
+
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(HashMap<String, Value>),
+}
+
+// Just enough JSON to read back what to_json writes, or a hand edited copy of it
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn new(json: &str) -> Parser<'_> {
+        Parser { chars: json.chars().peekable() }
+    }
+
+    fn parse_document(&mut self) -> Result<Value, Box<dyn Error>> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        match self.chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected {:?} after the end of the view state", c).into()),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Box<dyn Error>> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expected {:?} in view state, found {:?}", expected, c).into()),
+            None => Err(format!("Expected {:?} in view state, found the end", expected).into()),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, Box<dyn Error>> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some('t' | 'f' | 'n') => self.parse_literal(),
+            Some(_) => self.parse_number(),
+            None => Err("Unexpected end of view state".into()),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, Box<dyn Error>> {
+        self.expect('{')?;
+        let mut fields = HashMap::new();
+
+        self.skip_whitespace();
+        if self.chars.next_if_eq(&'}').is_some() {
+            return Ok(Value::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let name = self.parse_string()?;
+            self.expect(':')?;
+            fields.insert(name, self.parse_value()?);
+
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(fields)),
+                _ => return Err("Expected ',' or '}' in view state".into()),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, Box<dyn Error>> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+
+        self.skip_whitespace();
+        if self.chars.next_if_eq(&']').is_some() {
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.parse_value()?);
+
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err("Expected ',' or ']' in view state".into()),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, Box<dyn Error>> {
+        self.expect('"')?;
+        let mut value = String::new();
+
+        loop {
+            match self.chars.next() {
+                None => return Err("Unterminated string in view state".into()),
+                Some('"') => return Ok(value),
+                Some('\\') => {
+                    let escaped = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let hex: String = self.chars.by_ref().take(4).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| format!("Invalid escape \\u{} in view state", hex))?
+                        }
+                        other => return Err(format!("Invalid escape {:?} in view state", other).into()),
+                    };
+                    value.push(escaped);
+                }
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Value, Box<dyn Error>> {
+        let mut word = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphabetic()) {
+            word.push(c);
+        }
+
+        match word.as_str() {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            "null" => Ok(Value::Null),
+            _ => Err(format!("Unexpected {:?} in view state", word).into()),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, Box<dyn Error>> {
+        let mut number = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+            number.push(c);
+        }
+
+        number
+            .parse::<f64>()
+            .map(Value::Number)
+            .map_err(|_| format!("Invalid number {:?} in view state", number).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printed_view_state_parses_back() {
+        let view = ViewState::parse(r#"{"offset_x": 12.5, "offset_y": -3, "zoom": 2, "layers": [1, 0.5]}"#).unwrap();
+        let view = ViewState::parse(&view.to_json()).unwrap();
+        assert_eq!((view.offset_x, view.offset_y, view.zoom), (12.5, -3.0, 2.0));
+        assert_eq!(view.layer_opacity, [1.0, 0.5]);
+    }
+
+    #[test]
+    fn numbers_out_of_range_are_rejected() {
+        for json in [r#"{"zoom": 1e999}"#, r#"{"offset_x": -1e999}"#, r#"{"offset_y": 1e300}"#] {
+            assert!(ViewState::parse(json).is_err(), "{} was accepted", json);
+        }
+    }
+}
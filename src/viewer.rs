@@ -3,201 +3,2277 @@
 //
 // SPDX-License-Identifier: MIT
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use arboard::Clipboard;
+use image::RgbaImage;
+use log::{error, info, warn};
 
 use rfd::FileDialog;
 use speedy2d::color::Color;
-use speedy2d::font::{Font, TextLayout, TextOptions};
+use speedy2d::dimen::{UVec2, Vec2};
+use speedy2d::shape::Rectangle;
+use speedy2d::font::{Font, FormattedTextBlock, TextLayout, TextOptions};
 use speedy2d::Graphics2D;
 use speedy2d::image::{ImageDataType, ImageHandle, ImageSmoothingMode};
-use speedy2d::window::{KeyScancode, UserEventSender, VirtualKeyCode, WindowHandler, WindowHelper};
+use speedy2d::window::{
+    KeyScancode, ModifiersState, MouseButton, MouseScrollDistance, UserEventSender, VirtualKeyCode,
+    WindowFullscreenMode, WindowHandler, WindowHelper, WindowStartupInfo,
+};
+
+use kknd2_mapview::map::{save_map, DecodeOptions, LoadOptions, Map, MapLayer, SUPPORTED_EXTENSIONS};
+use kknd2_mapview::palette::{load_palette, save_palette, Colour};
+use kknd2_mapview::source::{self, load_map_from, ReadSeek, ZipEntry};
+use kknd2_mapview::viewport::{self, visible_tile_range, Dimensions, TileRange};
+
+use crate::browser::{describe_tile, draw_outline, TileBrowser};
+use crate::directory::DirectoryBrowser;
+use crate::history::{EditHistory, TileChange, DEFAULT_UNDO_DEPTH};
+use crate::palette_editor::{adjust_channel, Channel, PaletteEditor};
+use crate::cli::Options;
+use crate::recent::add_recent_file;
+use crate::view_state::ViewState;
+
+pub const WINDOW_TITLE: &str = "KKnD 2 Map Viewer";
+const LAYER_OPACITY_STEP: f32 = 0.1;
+// Tints for the layer colour-coding view, the base layer is left untinted
+const LAYER_TINTS: [(f32, f32, f32); 4] = [(1.0, 1.0, 1.0), (1.0, 0.45, 0.45), (0.45, 1.0, 0.45), (0.45, 0.6, 1.0)];
+const NOTICE_DURATION: Duration = Duration::from_secs(2);
+// A key down only counts as auto-repeat this soon after the previous one for the same
+// key. The slowest OS repeat delays are shorter, and a release missed while another
+// window had focus, e.g. after Alt+Tab, then can't swallow the next real press
+const KEY_REPEAT_TIMEOUT: Duration = Duration::from_secs(2);
+const STATUS_BAR_HEIGHT: f32 = 24.0;
+const STATUS_BAR_FONT_SIZE: f32 = 16.0;
+// How far past the map edges the view can scroll when overscroll is enabled, in screen pixels
+const OVERSCROLL_MARGIN: f32 = 128.0;
+const TILE_ID_FONT_SIZE: f32 = 10.0;
+const TILE_SPAN_BAR_HEIGHT: f32 = 24.0;
+const COMPASS_RADIUS: f32 = 24.0;
+// Full image rebuilds tried in a row after uploads fail, before settling for placeholders
+const MAX_IMAGE_REBUILDS: u32 = 3;
+// Tile images a map can need before duplicate tiles are merged, unless --max-textures
+// is given. Each image is its own texture, and drivers fail quietly past their limit
+const DEFAULT_MAX_TEXTURES: usize = 4096;
+// Pixels moved per frame while an arrow key is held, unless --pan-speed is given
+const DEFAULT_PAN_SPEED: u32 = 16;
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 8.0;
+// Zoom factor for one notch of the mouse wheel, unless --zoom-step is given
+const DEFAULT_ZOOM_STEP: f32 = 1.25;
+// Touchpads scroll in pixels, this many count as one wheel notch
+const PIXELS_PER_SCROLL_LINE: f32 = 40.0;
+// Grid spacings in base layer tiles that 'G' cycles through before turning the grid off
+const GRID_PRESETS: [u32; 4] = [1, 2, 5, 10];
+// Every this many grid lines is drawn as a thicker major line
+const GRID_MAJOR_EVERY: u32 = 10;
+// Minor lines closer together than this on screen are skipped, they would only be noise
+const MIN_GRID_LINE_GAP: f32 = 4.0;
+// Size of each palette index cell in the tile inspector
+const INSPECTOR_CELL_SIZE: f32 = 18.0;
+
+// Start the file dialog in the working directory, falling back to the user's home
+// directory if it has been removed or is otherwise inaccessible
+fn dialog_directory() -> Option<PathBuf> {
+    env::current_dir()
+        .ok()
+        .filter(|directory| directory.is_dir())
+        .or_else(|| env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(PathBuf::from))
+}
+
+// e.g. "LPS, LPC, LPM, and MAPD"
+fn supported_formats() -> String {
+    let formats: Vec<String> = SUPPORTED_EXTENSIONS.iter().map(|extension| extension.to_uppercase()).collect();
+    match formats.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{}, and {}", rest.join(", "), last),
+    }
+}
+
+// Keys that keep acting while held, e.g. panning or moving a selection. Every other
+// key is a discrete action that only fires when first pressed, so OS auto-repeat
+// can't rapidly flip a toggle back and forth
+fn is_continuous(key: VirtualKeyCode) -> bool {
+    matches!(key, VirtualKeyCode::Up | VirtualKeyCode::Down | VirtualKeyCode::Left | VirtualKeyCode::Right)
+}
+
+// Area enclosed by a polygon, by the shoelace formula. Corners can go either way
+// round, a self-intersecting outline gives the difference of its loops
+fn polygon_area(corners: &[Vec2]) -> f32 {
+    let twice_area: f32 = corners
+        .iter()
+        .zip(corners.iter().cycle().skip(1))
+        .map(|(a, b)| a.x * b.y - b.x * a.y)
+        .sum();
+    twice_area.abs() / 2.0
+}
+
+// Colour for a tile in layout only mode. The id is hashed so that neighbouring ids,
+// which are often similar tiles, still get colours that are easy to tell apart
+fn layout_colour(tile_id: u32, opacity: f32) -> Color {
+    let hash = (tile_id >> 2).wrapping_mul(0x9E37_79B1);
+    let channel = |shift: u32| 0.25 + ((hash >> shift) & 0xFF) as f32 / 340.0;
+    Color::from_rgba(channel(24), channel(16), channel(8), opacity)
+}
+
+// Each layer gets its own image of a tile it shares with another layer, see MapView::tiles
+fn texture_count(map: &Map) -> usize {
+    map.iter_tiles().count()
+}
+
+// Passed to the on_load callback once a map has been opened
+pub struct MapInfo {
+    pub name: String,
+    pub layers: usize,
+    // Of the base layer, in tiles
+    pub map_size: Dimensions,
+    pub pixel_size: Dimensions,
+}
+
+type OnLoad = Box<dyn FnMut(&MapInfo)>;
+
+#[derive(Clone)]
+pub enum MapSource {
+    File(PathBuf),
+    Zip { archive: PathBuf, entry: String },
+}
+
+impl MapSource {
+    fn name(&self) -> String {
+        match self {
+            MapSource::File(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            MapSource::Zip { entry, .. } => entry.clone(),
+        }
+    }
+}
+
+impl source::MapSource for MapSource {
+    fn open(&self) -> Result<Box<dyn ReadSeek>, Box<dyn Error>> {
+        match self {
+            MapSource::File(path) => source::MapSource::open(path.as_path()),
+            MapSource::Zip { archive, entry } => ZipEntry { archive, entry }.open(),
+        }
+    }
+}
+
+// View preferences are global, they are kept when another map is opened
+#[derive(Default)]
+struct ViewPreferences {
+    tint_layers: bool,
+    // Allow scrolling a little past the map edges, instead of stopping flush with them
+    overscroll: bool,
+    // Label every tile on screen with its id, for matching the tile map against the image
+    show_tile_ids: bool,
+    // Show the right half of the window as the selected layer's tile ids, colour-coded
+    split_tile_ids: bool,
+    // Outline each layer's extent, for maps whose layers differ in size
+    show_layer_extents: bool,
+    // Bar showing where each tile sits in the map data, for researching the file layout
+    show_tile_spans: bool,
+    // Decoded colour drawn as transparent, for maps that use a key colour such as magenta
+    transparent_colour: Option<(u8, u8, u8)>,
+    // Luminance cutoff below which colours fade out, from --dark-threshold
+    dark_threshold: Option<u8>,
+    // Draw palette index 0 and empty base layer cells in palette[0]'s colour
+    opaque_index_zero: bool,
+    // Base layer tiles between grid lines, or None with the grid hidden
+    grid_spacing: Option<u32>,
+    // The compass is shown unless turned off, it is the only hint that the view is flipped
+    hide_compass: bool,
+}
+
+// State is split three ways:
+// - per-map state describes the loaded map and how it is being inspected, and is
+//   reset by reset_map_state whenever a map is opened
+// - preferences persist across map switches
+// - input and render bookkeeping (held keys, mouse position, dirty flag)
+pub struct MapView {
+    // Per-map state
+    map: Option<Map>,
+    map_source: Option<MapSource>,
+    // Keyed by (layer index, tile id). The same id can be read at another tile size or
+    // decoded with another palette offset in each layer, so images aren't shared
+    tiles: HashMap<(usize, u32), ImageHandle>,
+    // Tiles whose image couldn't be created, drawn as placeholders
+    failed_images: HashSet<(usize, u32)>,
+    images_loaded: bool,
+    // Rebuilds tried since uploads started failing, see load_images
+    image_rebuilds: u32,
+    // View-space pixel position of the window's top left corner, negative when
+    // overscrolled past the top or left edge. Kept fractional so zooming about the
+    // cursor doesn't drift, it is only rounded to pixels when drawing
+    offset_x: f32,
+    offset_y: f32,
+    // Screen pixels per world pixel
+    zoom: f32,
+    // Mirror the view across the map's vertical or horizontal centre line. View space
+    // is world space with these flips applied, the map data itself is never changed
+    flip_h: bool,
+    flip_v: bool,
+    // World-space pixel positions of the distance measurement end points
+    measure_points: Vec<Vec2>,
+    // World-space pixel positions of the area measurement's corners, in click order
+    area_points: Vec<Vec2>,
+    // Set once Enter closes the polygon, the next Shift+click starts a new one
+    area_closed: bool,
+    layer_opacity: Vec<f32>,
+    selected_layer: usize,
+    browser: Option<TileBrowser>,
+    // (layer, tile id) whose raw palette indices are shown in the inspector
+    inspected_tile: Option<(usize, u32)>,
+    // Tile id painted into the selected layer by Ctrl+click, picked in the tile browser
+    brush: Option<u32>,
+    // Brush edits for Ctrl+Z and Ctrl+Y
+    history: EditHistory,
+    palette_editor: Option<PaletteEditor>,
+    // Colours changed in the palette editor, used in place of the loaded palette
+    edited_palette: Option<Vec<Colour>>,
+    // From Map::tile_spans, kept for the tile data bar
+    tile_spans: Vec<(u32, u32)>,
+
+    preferences: ViewPreferences,
+    palette_path: Option<PathBuf>,
+    // Colours read from palette_path, kept so tiles can be re-decoded without reading it again
+    palette_override: Option<Vec<Colour>>,
+    palette_alpha: bool,
+    allow_truncated: bool,
+    uniform_threshold: Option<f32>,
+    // Layers to load from each map, from --layers
+    layer_filter: Option<Vec<usize>>,
+    // Tile size read in place of each layer's, from --tile-size
+    tile_size_override: Option<(u32, u32)>,
+    // Load maps without their tiles, drawing each cell in a colour picked from its tile id
+    layout_only: bool,
+    verbose: bool,
+    // Count displayed y coordinates up from the bottom edge, as some editors do
+    origin_bottom_left: bool,
+    // Screen pixels per frame, so panning feels the same at any zoom
+    pan_speed: f32,
+    // Zoom factor per mouse wheel notch
+    zoom_step: f32,
+    // Skip creating tile images and draw flat placeholders, to profile the draw loop on its own
+    placeholder_tiles: bool,
+    dedup_tiles: bool,
+    max_textures: usize,
+
+    pan_up: bool,
+    pan_down: bool,
+    pan_left: bool,
+    pan_right: bool,
+    mouse_position: Vec2,
+    // Last pixel value shown in the status bar, so moving the mouse only redraws when it changes
+    hovered_pixel: Option<(u8, u8, u8, u8)>,
+    // Set while Ctrl+dragging with a brush, so each cell passed over is painted
+    painting: bool,
+    modifiers: ModifiersState,
+    // Keys currently held down and when each last went down, for telling auto-repeat
+    // apart from a fresh press
+    held_keys: HashMap<VirtualKeyCode, Instant>,
+    // Map files in the last opened directory, kept while a map from it is viewed so
+    // the list can be brought back without picking the directory again
+    directory_browser: Option<DirectoryBrowser>,
+    show_directory: bool,
+    // Size of the window before going fullscreen, None while windowed
+    windowed_size: Option<UVec2>,
+    // Ratio of physical to logical pixels, text is scaled by this to stay crisp on HiDPI displays
+    scale_factor: f32,
+    // Set whenever something that affects the rendered image changes
+    dirty: bool,
+    // Short confirmation message shown above the status bar, e.g. after copying
+    notice: Option<(String, Instant)>,
+    // Kept alive for the whole session, X11 clipboard contents vanish with their owner
+    clipboard: Option<Clipboard>,
+    // From --view, applied to the first map that loads
+    pending_view: Option<ViewState>,
+
+    // Called after every successful load, for harnesses driving the viewer
+    on_load: Option<OnLoad>,
+
+    font: Option<Font>,
+    event_sender: UserEventSender<MapViewEvent>
+}
+
+#[derive(Debug)]
+pub enum MapViewEvent {
+    OpenMap,
+    // Reopen the current map from disk, e.g. after the file changed
+    Reload,
+    ExportPalette,
+    SaveMap,
+    OpenDirectory,
+    // A directory browser thumbnail finished rendering, or failed to
+    ThumbnailRendered(PathBuf, Result<RgbaImage, String>),
+}
+
+impl MapView {
+    pub fn new(font: Option<Font>, event_sender: UserEventSender<MapViewEvent>, options: &Options) -> MapView {
+        MapView {
+            map: None,
+            map_source: None,
+            tiles: Default::default(),
+            failed_images: HashSet::new(),
+            images_loaded: false,
+            image_rebuilds: 0,
+            offset_x: 0.0,
+            offset_y: 0.0,
+            zoom: 1.0,
+            flip_h: false,
+            flip_v: false,
+            measure_points: Vec::new(),
+            area_points: Vec::new(),
+            area_closed: false,
+            layer_opacity: Vec::new(),
+            selected_layer: 0,
+            browser: None,
+            inspected_tile: None,
+            brush: None,
+            history: EditHistory::new(options.undo_depth.unwrap_or(DEFAULT_UNDO_DEPTH)),
+            palette_editor: None,
+            edited_palette: None,
+            tile_spans: Vec::new(),
+
+            preferences: ViewPreferences {
+                dark_threshold: options.dark_threshold,
+                grid_spacing: options.grid_spacing,
+                ..ViewPreferences::default()
+            },
+            palette_path: options.palette.clone(),
+            palette_override: None,
+            palette_alpha: options.palette_alpha,
+            allow_truncated: options.allow_truncated,
+            uniform_threshold: options.uniform_threshold,
+            layer_filter: options.layers.clone(),
+            tile_size_override: options.tile_size,
+            layout_only: options.layout_only,
+            verbose: options.verbosity > 0,
+            origin_bottom_left: options.origin_bottom_left,
+            placeholder_tiles: options.placeholder_tiles,
+            dedup_tiles: options.dedup_tiles,
+            max_textures: options.max_textures.unwrap_or(DEFAULT_MAX_TEXTURES),
+            pan_speed: options.pan_speed.unwrap_or(DEFAULT_PAN_SPEED) as f32,
+            zoom_step: options.zoom_step.unwrap_or(DEFAULT_ZOOM_STEP),
+
+            pan_up: false,
+            pan_down: false,
+            pan_left: false,
+            pan_right: false,
+            mouse_position: Vec2::ZERO,
+            hovered_pixel: None,
+            painting: false,
+            modifiers: ModifiersState::default(),
+            held_keys: HashMap::new(),
+            directory_browser: None,
+            show_directory: false,
+            windowed_size: None,
+            scale_factor: 1.0,
+            dirty: true,
+            notice: None,
+            clipboard: None,
+
+            pending_view: None,
+            on_load: None,
+
+            font,
+            event_sender
+        }
+    }
+
+    // Restores a view printed with 'W' once the next map has loaded and is drawn, when
+    // the window size is known to clamp the offsets to
+    pub fn restore_view(&mut self, view: ViewState) {
+        self.pending_view = Some(view);
+    }
+
+    fn view_state(&self) -> Option<ViewState> {
+        let (file, zip) = match self.map_source.as_ref()? {
+            MapSource::File(path) => (Some(path.clone()), None),
+            MapSource::Zip { archive, entry } => (None, Some((archive.clone(), entry.clone()))),
+        };
+
+        Some(ViewState {
+            file,
+            zip,
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            zoom: self.zoom,
+            flip_h: self.flip_h,
+            flip_v: self.flip_v,
+            layer_opacity: self.layer_opacity.clone(),
+        })
+    }
+
+    fn apply_view_state(&mut self, view: ViewState, window_size: UVec2) {
+        self.offset_x = view.offset_x;
+        self.offset_y = view.offset_y;
+        self.zoom = view.zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+        // A view printed at another window size, or for another map, may be out of range
+        self.clamp_offset(window_size);
+        self.flip_h = view.flip_h;
+        self.flip_v = view.flip_v;
+        // Layers the map doesn't have are ignored, missing ones stay fully visible
+        for (opacity, restored) in self.layer_opacity.iter_mut().zip(view.layer_opacity) {
+            *opacity = restored;
+        }
+    }
+
+    // One line of JSON on stdout, which --view accepts to get back to the same view
+    fn print_view_state(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        if let Some(view) = self.view_state() {
+            println!("{}", view.to_json());
+            self.show_notice(helper, "View state printed".to_string());
+        }
+    }
+
+    pub fn set_on_load(&mut self, on_load: impl FnMut(&MapInfo) + 'static) {
+        self.on_load = Some(Box::new(on_load));
+    }
+
+    fn read_palette_override(&mut self) {
+        self.palette_override = self.palette_path.as_ref().and_then(|path| match load_palette(path) {
+            Ok(palette) => Some(palette),
+            Err(e) => {
+                warn!("{}, using the embedded palette", e);
+                None
+            }
+        });
+    }
+
+    fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            palette: self.palette_override.clone(),
+            palette_alpha: self.palette_alpha,
+            allow_truncated: self.allow_truncated,
+            uniform_threshold: self.uniform_threshold,
+            layers: self.layer_filter.clone(),
+            tile_size: self.tile_size_override,
+            layout_only: self.layout_only,
+        }
+    }
+
+    fn decode_options(&self) -> DecodeOptions {
+        DecodeOptions {
+            transparent_colour: self.preferences.transparent_colour,
+            dark_threshold: self.preferences.dark_threshold,
+            opaque_index_zero: self.preferences.opaque_index_zero,
+            ..DecodeOptions::default()
+        }
+    }
+
+    pub fn open_map(&mut self, source: MapSource) {
+        self.read_palette_override();
+        let map = load_map_from(&source, &self.load_options());
+
+        match map {
+            Ok(mut map) => {
+                info!("Loaded {}, {} layers", source.name(), map.layers.len());
+                if self.dedup_tiles {
+                    let merged = map.deduplicate_tiles();
+                    info!("Merged {} duplicate tiles in {}", merged, source.name());
+                }
+
+                // Merging is the only way to need fewer textures, so it is done regardless
+                // of --dedup-tiles once there are too many
+                let mut textures = texture_count(&map);
+                if textures > self.max_textures && !self.dedup_tiles {
+                    let merged = map.deduplicate_tiles();
+                    warn!(
+                        "{} needs {} tile images, more than the limit of {}, merged {} duplicate tiles",
+                        source.name(),
+                        textures,
+                        self.max_textures,
+                        merged
+                    );
+                    textures = texture_count(&map);
+                }
+
+                if let MapSource::File(path) = &source {
+                    add_recent_file(path);
+                }
+                self.reset_map_state(map, source);
+
+                // Maps are loaded with the plain palette, the key colour and dark
+                // threshold are applied on top
+                if self.preferences.transparent_colour.is_some()
+                    || self.preferences.dark_threshold.is_some()
+                    || self.preferences.opaque_index_zero
+                {
+                    self.redecode_tiles();
+                }
+
+                if textures > self.max_textures {
+                    let message = format!(
+                        "{} tile images, more than the limit of {}: some tiles may not appear",
+                        textures, self.max_textures
+                    );
+                    warn!("{}", message);
+                    self.notice = Some((message, Instant::now()));
+                }
+
+                if self.verbose {
+                    self.print_validation();
+                }
+
+                if let (Some(on_load), Some(map), Some(source)) = (&mut self.on_load, &self.map, &self.map_source) {
+                    on_load(&MapInfo {
+                        name: source.name(),
+                        layers: map.layers.len(),
+                        map_size: map.layers[0].map_size,
+                        pixel_size: map.pixel_size(),
+                    });
+                }
+            }
+            Err(e) => error!("Failed to load {}: {}", source.name(), e),
+        }
+    }
+
+    // Lists the maps in a directory in place of the current view
+    pub fn open_directory(&mut self, directory: &Path) {
+        match DirectoryBrowser::open(directory, self.event_sender.clone()) {
+            Ok(browser) => {
+                info!("Opened directory {}", directory.display());
+                self.directory_browser = Some(browser);
+                self.show_directory = true;
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    // Shows the last directory again, or asks for one if none has been opened
+    fn toggle_directory_browser(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        if self.directory_browser.is_none() || self.modifiers.shift() {
+            self.event_sender.send_event(MapViewEvent::OpenDirectory).unwrap();
+            return;
+        }
+
+        self.show_directory = !self.show_directory;
+        self.invalidate(helper);
+    }
+
+    fn on_key_down_directory(&mut self, helper: &mut WindowHelper<MapViewEvent>, key: VirtualKeyCode) {
+        let window_size = helper.get_size_pixels();
+        let scale_factor = self.scale_factor;
+        let browser = match &mut self.directory_browser {
+            None => return,
+            Some(browser) => browser,
+        };
+
+        match key {
+            VirtualKeyCode::Up => browser.move_selection(0, -1, window_size, scale_factor),
+            VirtualKeyCode::Down => browser.move_selection(0, 1, window_size, scale_factor),
+            VirtualKeyCode::Left => browser.move_selection(-1, 0, window_size, scale_factor),
+            VirtualKeyCode::Right => browser.move_selection(1, 0, window_size, scale_factor),
+            VirtualKeyCode::Return => {
+                if let Some(path) = browser.selected().map(Path::to_path_buf) {
+                    self.open_map_with_title(helper, MapSource::File(path.clone()));
+                    // The list stays up if the map failed to load
+                    self.show_directory = !matches!(&self.map_source, Some(MapSource::File(opened)) if *opened == path);
+                }
+            }
+            VirtualKeyCode::D if self.modifiers.shift() => {
+                self.event_sender.send_event(MapViewEvent::OpenDirectory).unwrap();
+            }
+            VirtualKeyCode::D | VirtualKeyCode::Escape => self.show_directory = false,
+            _ => return,
+        }
+        self.invalidate(helper);
+    }
+
+    fn draw_directory_browser(&mut self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let scale_factor = self.scale_factor;
+        let browser = match &mut self.directory_browser {
+            None => return,
+            Some(browser) => browser,
+        };
+
+        let header = if browser.is_empty() {
+            format!("No supported maps in {}", browser.directory().display())
+        } else {
+            browser.directory().display().to_string()
+        };
+        let header_position = browser.header_position(scale_factor);
+
+        browser.request_visible(window_size, scale_factor);
+        let labels = browser.draw(graphics, window_size, scale_factor);
+
+        if let Some(text) = self.layout_text(&header, 20.0) {
+            graphics.draw_text(header_position, Color::WHITE, &text);
+        }
+
+        for (name, position) in labels {
+            if let Some(text) = self.layout_text(&name, STATUS_BAR_FONT_SIZE) {
+                graphics.draw_text(position, Color::WHITE, &text);
+            }
+        }
+    }
+
+    fn reset_map_state(&mut self, map: Map, source: MapSource) {
+        self.layer_opacity = vec![1.0; map.layers.len()];
+        self.selected_layer = 0;
+        self.tile_spans = map.tile_spans();
+        self.map = Some(map);
+        self.map_source = Some(source);
+        self.tiles.clear();
+        self.failed_images.clear();
+        self.images_loaded = false;
+        self.image_rebuilds = 0;
+        self.offset_x = 0.0;
+        self.offset_y = 0.0;
+        self.zoom = 1.0;
+        self.flip_h = false;
+        self.flip_v = false;
+        self.measure_points.clear();
+        self.clear_area();
+        self.browser = None;
+        self.inspected_tile = None;
+        self.brush = None;
+        self.history.clear();
+        self.painting = false;
+        self.palette_editor = None;
+        self.edited_palette = None;
+    }
+
+    // Loading blocks the event loop, so the title is the only feedback that can be
+    // shown while a large archive is being decompressed
+    fn open_map_with_title(&mut self, helper: &mut WindowHelper<MapViewEvent>, source: MapSource) {
+        helper.set_title(format!("Loading {}… — {}", source.name(), WINDOW_TITLE));
+        self.open_map(source);
+        self.update_title(helper);
+    }
+
+    fn update_title(&self, helper: &mut WindowHelper<MapViewEvent>) {
+        match &self.map_source {
+            None => helper.set_title(WINDOW_TITLE),
+            Some(source) => helper.set_title(format!("{} — {}", source.name(), WINDOW_TITLE)),
+        }
+    }
+
+    fn text_size(&self, size: f32) -> f32 {
+        size * self.scale_factor
+    }
+
+    // Text is left out entirely when no font could be loaded
+    fn layout_text(&self, text: &str, size: f32) -> Option<FormattedTextBlock> {
+        let font = self.font.as_ref()?;
+        Some(font.layout_text(text, self.text_size(size), TextOptions::new()))
+    }
+
+    fn invalidate(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.dirty = true;
+        helper.request_redraw();
+    }
+
+    fn print_validation(&self) {
+        if let Some(map) = &self.map {
+            for (l, layer) in map.layers.iter().enumerate() {
+                let stats = layer.stats();
+                println!(
+                    "Layer {}: {} empty cells, {} decoded, {} missing, data at {:#x}, {} bytes",
+                    l, stats.empty, stats.decoded, stats.missing, layer.data_offset, layer.data_len
+                );
+            }
+
+            match map.content_bounds() {
+                Some((min, max)) => {
+                    let (min, max) = if self.origin_bottom_left {
+                        let height = map.pixel_size().height;
+                        ((min.0, height - max.1), (max.0, height - min.1))
+                    } else {
+                        (min, max)
+                    };
+                    println!(
+                        "Content bounds: ({}, {}) to ({}, {}) pixels, {}x{}",
+                        min.0,
+                        min.1,
+                        max.0,
+                        max.1,
+                        max.0 - min.0,
+                        max.1 - min.1
+                    );
+                }
+                None => println!("Content bounds: map has no tiles"),
+            }
+
+            // Every tile would be listed as not loaded
+            if self.layout_only {
+                println!("Tiles aren't loaded in layout only mode, 'Shift+F5' reloads them");
+                return;
+            }
+
+            let problems = map.validate();
+            if problems.is_empty() {
+                println!("Map validated, all referenced tiles are loaded");
+            }
+            for problem in problems {
+                println!("{}", problem);
+            }
+        }
+    }
+
+    // Returns the RGBA value of the topmost opaque pixel under a screen position
+    fn pixel_at_screen(&self, position: Vec2) -> Option<(u8, u8, u8, u8)> {
+        let map = self.map.as_ref()?;
+        let world = self.screen_to_world(position);
+        if world.x < 0.0 || world.y < 0.0 {
+            return None;
+        }
+
+        map.layers.iter().rev().find_map(|layer| {
+            let tile_x = world.x as u32 / layer.tile_size.width;
+            let tile_y = world.y as u32 / layer.tile_size.height;
+            let tile = layer.tiles.get(&layer.tile_at(tile_x, tile_y)?)?;
+            let pixel_x = world.x as u32 % layer.tile_size.width;
+            let pixel_y = world.y as u32 % layer.tile_size.height;
+            let index = layer.tile_size.index_of(pixel_x, pixel_y) * 4;
+            match tile.pixels.get(index..index + 4)? {
+                [_, _, _, 0] => None,
+                [r, g, b, a] => Some((*r, *g, *b, *a)),
+                _ => None,
+            }
+        })
+    }
+
+    // Picks the colour under the cursor as the transparent colour, or clears it if one is already set
+    fn toggle_transparent_colour(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        let message = if self.preferences.transparent_colour.take().is_some() {
+            "Transparent colour cleared".to_string()
+        } else {
+            match self.pixel_at_screen(self.mouse_position) {
+                None => return,
+                Some((r, g, b, _)) => {
+                    self.preferences.transparent_colour = Some((r, g, b));
+                    format!("Drawing #{:02x}{:02x}{:02x} as transparent", r, g, b)
+                }
+            }
+        };
+
+        self.redecode_tiles();
+        self.show_notice(helper, message);
+    }
+
+    fn toggle_opaque_index_zero(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.preferences.opaque_index_zero = !self.preferences.opaque_index_zero;
+        self.redecode_tiles();
+
+        let message = if self.preferences.opaque_index_zero {
+            "Drawing palette index 0 in its colour"
+        } else {
+            "Drawing palette index 0 as transparent"
+        };
+        self.show_notice(helper, message.to_string());
+    }
+
+    // The palette tiles are decoded with, including any edits
+    fn current_palette(&self) -> Option<Vec<Colour>> {
+        match (&self.edited_palette, &self.map) {
+            (Some(palette), _) => Some(palette.clone()),
+            (None, Some(map)) => Some(map.effective_palette(self.palette_override.as_deref())),
+            (None, None) => None,
+        }
+    }
+
+    // Rebuilds every tile from its palette indices, after the palette or decode options change
+    fn redecode_tiles(&mut self) {
+        let options = self.decode_options();
+        let palette = self.current_palette();
+        if let (Some(map), Some(palette)) = (&mut self.map, palette) {
+            map.redecode(&palette, &options);
+        }
+
+        self.tiles.clear();
+        self.failed_images.clear();
+        self.images_loaded = false;
+    }
+
+    fn reload_palette(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        if self.map.is_none() {
+            return;
+        }
+
+        // The reloaded file replaces any edits made in the palette editor
+        self.read_palette_override();
+        self.edited_palette = None;
+        self.redecode_tiles();
+        self.invalidate(helper);
+    }
+
+    // Returns (layer, tile x, tile y, tile id) of the topmost non-empty tile under a screen position
+    fn tile_at_screen(&self, position: Vec2) -> Option<(usize, u32, u32, u32)> {
+        let map = self.map.as_ref()?;
+        let world = self.screen_to_world(position);
+        if world.x < 0.0 || world.y < 0.0 {
+            return None;
+        }
+
+        map.layers.iter().enumerate().rev().find_map(|(l, layer)| {
+            let tile_x = world.x as u32 / layer.tile_size.width;
+            let tile_y = world.y as u32 / layer.tile_size.height;
+            let tile_id = layer.tile_at(tile_x, tile_y)?;
+            (tile_id != 0).then_some((l, tile_x, tile_y, tile_id))
+        })
+    }
+
+    // Screen pixels the view can scroll past the map edges
+    fn overscroll_margin(&self) -> f32 {
+        if self.preferences.overscroll {
+            OVERSCROLL_MARGIN
+        } else {
+            0.0
+        }
+    }
+
+    fn offset_limits(&self, window_length: u32, map_length: u32) -> (f32, f32) {
+        viewport::offset_limits(window_length, map_length, self.zoom, self.overscroll_margin())
+    }
+
+    // Keeps the offsets within offset_limits, e.g. after overscroll is turned off
+    fn clamp_offset(&mut self, window_size: UVec2) {
+        let map_pixels = match &self.map {
+            None => return,
+            Some(map) => map.pixel_size(),
+        };
+
+        let window_size = Dimensions::new(window_size.x, window_size.y);
+        (self.offset_x, self.offset_y) = viewport::clamp_offset(
+            (self.offset_x, self.offset_y),
+            window_size,
+            map_pixels,
+            self.zoom,
+            self.overscroll_margin(),
+        );
+    }
+
+    // Moves the view by a distance in screen pixels
+    fn pan_by(&mut self, helper: &mut WindowHelper<MapViewEvent>, distance: Vec2) {
+        self.offset_x += distance.x / self.zoom;
+        self.offset_y += distance.y / self.zoom;
+        self.clamp_offset(helper.get_size_pixels());
+        self.invalidate(helper);
+    }
+
+    // Scrolls so that a world-space point is in the middle of the window, as far as
+    // the offset limits allow
+    fn center_on(&mut self, helper: &mut WindowHelper<MapViewEvent>, point: Vec2) {
+        let window_size = helper.get_size_pixels();
+        let point = self.flip(point);
+        self.offset_x = point.x - window_size.x as f32 / (2.0 * self.zoom);
+        self.offset_y = point.y - window_size.y as f32 / (2.0 * self.zoom);
+        self.clamp_offset(window_size);
+        self.invalidate(helper);
+    }
+
+    // Returns to 100% zoom and centers the map in the window, or pins it to the top
+    // left if it is smaller than the window and can't be scrolled past its edges
+    fn reset_view(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        let map_pixels = match &self.map {
+            None => return,
+            Some(map) => map.pixel_size(),
+        };
+
+        self.zoom = 1.0;
+        self.measure_points.clear();
+        self.clear_area();
+        self.center_on(helper, Vec2::new(map_pixels.width as f32, map_pixels.height as f32) / 2.0);
+    }
+
+    // Zooms to fit the non-empty part of the map in the window and centers on it,
+    // ignoring empty margins
+    fn fit_to_content(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        let bounds = match &self.map {
+            None => return,
+            Some(map) => map.content_bounds(),
+        };
+
+        match bounds {
+            None => self.show_notice(helper, "Map has no tiles".to_string()),
+            Some((min, max)) => {
+                let min = Vec2::new(min.0 as f32, min.1 as f32);
+                let max = Vec2::new(max.0 as f32, max.1 as f32);
+                let window_size = helper.get_size_pixels().into_f32();
+                let zoom = (window_size.x / (max.x - min.x)).min(window_size.y / (max.y - min.y));
+
+                self.zoom = zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+                self.measure_points.clear();
+                self.clear_area();
+                let center = (min + max) / 2.0;
+                self.center_on(helper, center);
+            }
+        }
+    }
+
+    // Jumps to the middle of the base layer's content, for sparse maps that open on empty space
+    fn goto_center_of_mass(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        let layer = match self.map.as_ref().and_then(|map| map.layers.first()) {
+            None => return,
+            Some(layer) => layer,
+        };
+
+        match layer.content_centroid() {
+            None => self.show_notice(helper, "Layer 1 has no tiles".to_string()),
+            Some((x, y)) => {
+                let point = Vec2::new(x * layer.tile_size.width as f32, y * layer.tile_size.height as f32);
+                self.center_on(helper, point);
+            }
+        }
+    }
+
+    // Switches between borderless fullscreen and the window size used before it
+    fn toggle_fullscreen(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        match self.windowed_size.take() {
+            None => {
+                self.windowed_size = Some(helper.get_size_pixels());
+                helper.set_fullscreen_mode(WindowFullscreenMode::FullscreenBorderless);
+            }
+            Some(size) => {
+                helper.set_fullscreen_mode(WindowFullscreenMode::Windowed);
+                helper.set_size_pixels(size);
+            }
+        }
+    }
+
+    // Mirrors the view about the point in the middle of the window, so it stays in place
+    fn toggle_flip(&mut self, helper: &mut WindowHelper<MapViewEvent>, vertical: bool) {
+        if self.map.is_none() {
+            return;
+        }
+
+        let center = self.screen_to_world(helper.get_size_pixels().into_f32() / 2.0);
+        let flipped = if vertical { &mut self.flip_v } else { &mut self.flip_h };
+        *flipped = !*flipped;
+
+        let message = match (vertical, *flipped) {
+            (false, true) => "Flipped horizontally",
+            (false, false) => "Horizontal flip off",
+            (true, true) => "Flipped vertically",
+            (true, false) => "Vertical flip off",
+        };
+        self.center_on(helper, center);
+        self.show_notice(helper, message.to_string());
+    }
+
+    fn toggle_overscroll(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.preferences.overscroll = !self.preferences.overscroll;
+        self.clamp_offset(helper.get_size_pixels());
+
+        let message = if self.preferences.overscroll {
+            "Scrolling past the map edges"
+        } else {
+            "Scrolling stops at the map edges"
+        };
+        self.show_notice(helper, message.to_string());
+    }
+
+    // Steps to the next wider grid preset, then turns the grid off
+    fn cycle_grid(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.preferences.grid_spacing = match self.preferences.grid_spacing {
+            None => Some(GRID_PRESETS[0]),
+            Some(spacing) => GRID_PRESETS.iter().copied().find(|preset| *preset > spacing),
+        };
+
+        let message = match self.preferences.grid_spacing {
+            Some(1) => "Grid on every tile".to_string(),
+            Some(spacing) => format!("Grid every {} tiles", spacing),
+            None => "Grid off".to_string(),
+        };
+        self.show_notice(helper, message);
+    }
+
+    // Reloads the map with or without its tiles, the view is kept as with 'F5'
+    fn toggle_layout_only(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.layout_only = !self.layout_only;
+        let message = if self.layout_only { "Layout only, tiles aren't loaded" } else { "Loading tiles" };
+        self.show_notice(helper, message.to_string());
+        self.event_sender.send_event(MapViewEvent::Reload).unwrap();
+    }
+
+    fn show_notice(&mut self, helper: &mut WindowHelper<MapViewEvent>, message: String) {
+        self.notice = Some((message, Instant::now()));
+        self.invalidate(helper);
+    }
+
+    fn copy_tile_under_cursor(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        let tile_id = match self.tile_at_screen(self.mouse_position) {
+            None => return,
+            Some((_, _, _, tile_id)) => tile_id,
+        };
+
+        if self.clipboard.is_none() {
+            self.clipboard = Clipboard::new().map_err(|e| warn!("Clipboard unavailable: {}", e)).ok();
+        }
+
+        let copied = match &mut self.clipboard {
+            None => return,
+            Some(clipboard) => clipboard.set_text(tile_id.to_string()),
+        };
+
+        match copied {
+            Ok(()) => self.show_notice(helper, format!("Copied id {}", tile_id)),
+            Err(e) => error!("Failed to copy tile id: {}", e),
+        }
+    }
+
+    // Paints the brush into the selected layer's cell under a screen position. A tile from
+    // another layer is copied into this one, so it is drawn and saved with it
+    fn paint_at_screen(&mut self, helper: &mut WindowHelper<MapViewEvent>, position: Vec2) {
+        let world = self.screen_to_world(position);
+        let (map, brush) = match (&mut self.map, self.brush) {
+            (Some(map), Some(brush)) if world.x >= 0.0 && world.y >= 0.0 => (map, brush),
+            _ => return,
+        };
+        let layer = match map.layers.get(self.selected_layer) {
+            None => return,
+            Some(layer) => layer,
+        };
+
+        let tile_x = (world.x / layer.tile_size.width as f32) as u32;
+        let tile_y = (world.y / layer.tile_size.height as f32) as u32;
+        let copied = !layer.tiles.contains_key(&brush);
+        let old = match map.paint_tile(self.selected_layer, tile_x, tile_y, brush) {
+            None => return,
+            Some(old) => old,
+        };
+
+        // Images are per layer, so a tile new to this layer needs one of its own
+        if copied {
+            self.images_loaded = false;
+        }
+        let change = TileChange { layer: self.selected_layer, x: tile_x, y: tile_y, old, new: brush };
+        self.history.record(change);
+        self.tile_spans = map.tile_spans();
+        self.invalidate(helper);
+    }
+
+    // Puts back the cells of the last brush edit, or paints them again for redo
+    fn undo_edit(&mut self, helper: &mut WindowHelper<MapViewEvent>, redo: bool) {
+        let map = match &mut self.map {
+            None => return,
+            Some(map) => map,
+        };
+        let changes = if redo { self.history.redo() } else { self.history.undo() };
+        let changes = match changes {
+            None => {
+                self.show_notice(helper, format!("Nothing to {}", if redo { "redo" } else { "undo" }));
+                return;
+            }
+            Some(changes) => changes,
+        };
+
+        // Undone in reverse, the opposite order to how the cells were painted
+        let count = changes.len();
+        let apply = |change: &TileChange| {
+            if let Some(layer) = map.layers.get_mut(change.layer) {
+                layer.set_tile(change.x, change.y, if redo { change.new } else { change.old });
+            }
+        };
+        if redo {
+            changes.iter().for_each(apply);
+        } else {
+            changes.iter().rev().for_each(apply);
+        }
+
+        self.tile_spans = map.tile_spans();
+        let verb = if redo { "Redid" } else { "Undid" };
+        self.show_notice(helper, format!("{} {} cell{}", verb, count, if count == 1 { "" } else { "s" }));
+    }
+
+    // Opens the inspector for the tile under the cursor, or closes it if it is open
+    fn toggle_inspector(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.inspected_tile = match self.inspected_tile {
+            Some(_) => None,
+            None => self
+                .tile_at_screen(self.mouse_position)
+                .map(|(layer, _, _, tile_id)| (layer, tile_id)),
+        };
+        self.invalidate(helper);
+    }
+
+    fn toggle_palette_editor(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        self.palette_editor = match (&self.palette_editor, self.current_palette()) {
+            (None, Some(palette)) => Some(PaletteEditor::new(&palette)),
+            _ => None,
+        };
+        self.invalidate(helper);
+    }
+
+    // Changes one channel of the selected palette entry and re-decodes the map with it
+    fn adjust_palette_entry(&mut self, helper: &mut WindowHelper<MapViewEvent>, channel: Channel, steps: i32) {
+        let (index, mut palette) = match (&self.palette_editor, self.current_palette()) {
+            (Some(editor), Some(palette)) => (editor.selection(), palette),
+            _ => return,
+        };
+
+        if let Some(colour) = palette.get_mut(index) {
+            adjust_channel(colour, channel, steps);
+            self.edited_palette = Some(palette);
+            self.redecode_tiles();
+            self.invalidate(helper);
+        }
+    }
+
+    fn reset_palette_edits(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        if self.edited_palette.take().is_some() {
+            self.redecode_tiles();
+            self.show_notice(helper, "Restored the original palette".to_string());
+        }
+    }
+
+    fn on_key_down_palette_editor(&mut self, helper: &mut WindowHelper<MapViewEvent>, key: VirtualKeyCode) {
+        // Shift lowers a channel instead of raising it
+        let steps = if self.modifiers.shift() { -1 } else { 1 };
+        let editor = match &mut self.palette_editor {
+            None => return,
+            Some(editor) => editor,
+        };
+
+        match key {
+            VirtualKeyCode::Up => editor.move_selection(0, -1),
+            VirtualKeyCode::Down => editor.move_selection(0, 1),
+            VirtualKeyCode::Left => editor.move_selection(-1, 0),
+            VirtualKeyCode::Right => editor.move_selection(1, 0),
+            VirtualKeyCode::R => self.adjust_palette_entry(helper, Channel::Red, steps),
+            VirtualKeyCode::G => self.adjust_palette_entry(helper, Channel::Green, steps),
+            VirtualKeyCode::B => self.adjust_palette_entry(helper, Channel::Blue, steps),
+            VirtualKeyCode::Backspace => self.reset_palette_edits(helper),
+            VirtualKeyCode::L | VirtualKeyCode::Escape => self.palette_editor = None,
+            _ => return,
+        }
+        self.invalidate(helper);
+    }
+
+    fn draw_palette_editor(&self, graphics: &mut Graphics2D) {
+        let (editor, palette) = match (&self.palette_editor, self.current_palette()) {
+            (Some(editor), Some(palette)) => (editor, palette),
+            _ => return,
+        };
+
+        editor.draw(graphics, &palette, self.scale_factor);
+
+        if let Some(colour) = palette.get(editor.selection()) {
+            let label = format!(
+                "Index {}: #{:02x}{:02x}{:02x}{}",
+                editor.selection(),
+                colour.r,
+                colour.g,
+                colour.b,
+                if self.edited_palette.is_some() { " (edited)" } else { "" }
+            );
+            if let Some(text) = self.layout_text(&label, STATUS_BAR_FONT_SIZE) {
+                graphics.draw_text(editor.label_position(self.scale_factor), Color::WHITE, &text);
+            }
+        }
+    }
+
+    // Draws the inspected tile's palette indices as a heatmap, labelled in hex
+    fn draw_inspector(&self, graphics: &mut Graphics2D) {
+        let (layer, tile_id) = match self.inspected_tile {
+            None => return,
+            Some(inspected_tile) => inspected_tile,
+        };
+
+        let layer = match self.map.as_ref().and_then(|map| map.layers.get(layer)) {
+            None => return,
+            Some(layer) => layer,
+        };
+
+        let tile = match layer.tiles.get(&tile_id) {
+            None => return,
+            Some(tile) => tile,
+        };
+
+        let cell_size = INSPECTOR_CELL_SIZE * self.scale_factor;
+        let margin = 16.0 * self.scale_factor;
+        let title_height = 24.0 * self.scale_factor;
+        let grid_top_left = Vec2::new(margin, margin + title_height);
+        let grid_size = Vec2::new(layer.tile_size.width as f32, layer.tile_size.height as f32) * cell_size;
+
+        graphics.draw_rectangle(
+            Rectangle::new(
+                Vec2::new(margin, margin) - Vec2::new(4.0, 4.0),
+                grid_top_left + grid_size + Vec2::new(4.0, 4.0),
+            ),
+            Color::from_rgba(0.0, 0.0, 0.0, 0.85),
+        );
+
+        if let Some(title) = self.layout_text(&format!("Tile {:#x} palette indices", tile_id), STATUS_BAR_FONT_SIZE) {
+            graphics.draw_text(Vec2::new(margin, margin), Color::WHITE, &title);
+        }
+
+        for (i, index) in tile.indices.iter().enumerate() {
+            let (column, row) = layer.tile_size.position_of(i);
+            let cell = grid_top_left + Vec2::new(column as f32, row as f32) * cell_size;
+
+            let shade = *index as f32 / 255.0;
+            graphics.draw_rectangle(Rectangle::new(cell, cell + Vec2::new(cell_size, cell_size)), Color::from_gray(shade));
+
+            if let Some(label) = self.layout_text(&format!("{:02x}", index), INSPECTOR_CELL_SIZE / 2.0) {
+                let text_colour = if shade > 0.5 { Color::BLACK } else { Color::WHITE };
+                let position = cell + (Vec2::new(cell_size, cell_size) - Vec2::new(label.width(), label.height())) / 2.0;
+                graphics.draw_text(position, text_colour, &label);
+            }
+        }
+    }
+
+    fn draw_notice(&mut self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let message = match &self.notice {
+            Some((message, shown)) if shown.elapsed() < NOTICE_DURATION => message,
+            _ => {
+                self.notice = None;
+                return;
+            }
+        };
+
+        let layout = match self.layout_text(message, STATUS_BAR_FONT_SIZE) {
+            None => return,
+            Some(layout) => layout,
+        };
+        let position = Vec2::new(
+            8.0 * self.scale_factor,
+            window_size.y as f32 - (STATUS_BAR_HEIGHT * self.scale_factor) - layout.height() - 8.0,
+        );
+        graphics.draw_text(position, Color::YELLOW, &layout);
+
+        // Keep redrawing until the notice expires
+        self.dirty = true;
+    }
+
+    fn select_layer(&mut self, helper: &mut WindowHelper<MapViewEvent>, layer: usize) {
+        if layer < self.layer_opacity.len() {
+            self.selected_layer = layer;
+            self.invalidate(helper);
+        }
+    }
+
+    fn adjust_layer_opacity(&mut self, helper: &mut WindowHelper<MapViewEvent>, delta: f32) {
+        if let Some(opacity) = self.layer_opacity.get_mut(self.selected_layer) {
+            *opacity = (*opacity + delta).clamp(0.0, 1.0);
+            self.invalidate(helper);
+        }
+    }
+
+    // Shifts the selected layer's palette indices and re-decodes, to test whether the
+    // layers index different parts of the palette
+    fn adjust_palette_offset(&mut self, helper: &mut WindowHelper<MapViewEvent>, delta: i32) {
+        let layer = match self.map.as_mut().and_then(|map| map.layers.get_mut(self.selected_layer)) {
+            None => return,
+            Some(layer) => layer,
+        };
+
+        layer.palette_offset = (layer.palette_offset as i32 + delta).rem_euclid(256) as u8;
+        let offset = layer.palette_offset;
+        self.redecode_tiles();
+        self.show_notice(helper, format!("Layer {} palette offset: {}", self.selected_layer + 1, offset));
+    }
+
+    fn layer_colour(&self, layer: usize, opacity: f32) -> Color {
+        let (r, g, b) = if self.preferences.tint_layers {
+            LAYER_TINTS[layer % LAYER_TINTS.len()]
+        } else {
+            (1.0, 1.0, 1.0)
+        };
+        Color::from_rgba(r, g, b, opacity)
+    }
+
+    // Forgets every held key. speedy2d doesn't report focus changes, so keys released
+    // while a modal dialog had the keyboard would otherwise stay held, panning on and
+    // swallowing their next press as auto-repeat
+    fn release_keys(&mut self) {
+        self.held_keys.clear();
+        self.pan_up = false;
+        self.pan_down = false;
+        self.pan_left = false;
+        self.pan_right = false;
+    }
+
+    fn is_panning(&self) -> bool {
+        self.pan_up || self.pan_down || self.pan_left || self.pan_right
+    }
+
+    // Converts between world and view space, flipping is its own inverse
+    fn flip(&self, position: Vec2) -> Vec2 {
+        let map_pixels = match &self.map {
+            None => return position,
+            Some(map) => map.pixel_size(),
+        };
+
+        Vec2::new(
+            if self.flip_h { map_pixels.width as f32 - position.x } else { position.x },
+            if self.flip_v { map_pixels.height as f32 - position.y } else { position.y },
+        )
+    }
+
+    fn screen_to_world(&self, position: Vec2) -> Vec2 {
+        self.flip(Vec2::new(position.x / self.zoom + self.offset_x, position.y / self.zoom + self.offset_y))
+    }
+
+    fn world_to_screen(&self, position: Vec2) -> Vec2 {
+        let position = self.flip(position);
+        Vec2::new((position.x - self.offset_x) * self.zoom, (position.y - self.offset_y) * self.zoom)
+    }
+
+    // Screen rectangle covered by a world-space rectangle. Both corners are rounded to
+    // whole pixels, so neighbouring tiles share an edge instead of leaving gaps at
+    // fractional zoom levels
+    fn world_rect_to_screen(&self, top_left: Vec2, size: Vec2) -> Rectangle {
+        let round = |position: Vec2| Vec2::new(position.x.round(), position.y.round());
+        let a = round(self.world_to_screen(top_left));
+        let b = round(self.world_to_screen(top_left + size));
+        Rectangle::new(Vec2::new(a.x.min(b.x), a.y.min(b.y)), Vec2::new(a.x.max(b.x), a.y.max(b.y)))
+    }
+
+    // Part of each tile image drawn into its screen rectangle, mirrored along with the view
+    fn tile_image_coords(&self) -> Rectangle {
+        let (left, right) = if self.flip_h { (1.0, 0.0) } else { (0.0, 1.0) };
+        let (top, bottom) = if self.flip_v { (1.0, 0.0) } else { (0.0, 1.0) };
+        Rectangle::new(Vec2::new(left, top), Vec2::new(right, bottom))
+    }
+
+    // Culls against the part of the world the window covers at the current zoom, the
+    // extra pixel covers the fractional part of the offset
+    fn layer_tile_range(&self, layer: &MapLayer, window_size: UVec2) -> TileRange {
+        let a = self.screen_to_world(Vec2::ZERO);
+        let b = self.screen_to_world(window_size.into_f32());
+        visible_tile_range(
+            (a.x.min(b.x).floor() as i32, a.y.min(b.y).floor() as i32),
+            Dimensions::new(
+                (window_size.x as f32 / self.zoom).ceil() as u32 + 1,
+                (window_size.y as f32 / self.zoom).ceil() as u32 + 1,
+            ),
+            layer.map_size,
+            layer.tile_size,
+        )
+    }
+
+    // Zooms by a factor, keeping the world position under a screen position fixed
+    fn zoom_at(&mut self, helper: &mut WindowHelper<MapViewEvent>, factor: f32, anchor: Vec2) {
+        let zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        if zoom == self.zoom {
+            return;
+        }
+
+        self.offset_x = viewport::zoom_anchor_offset(self.offset_x, anchor.x, self.zoom, zoom);
+        self.offset_y = viewport::zoom_anchor_offset(self.offset_y, anchor.y, self.zoom, zoom);
+        self.zoom = zoom;
+        self.clamp_offset(helper.get_size_pixels());
+        self.invalidate(helper);
+    }
+
+    // Labels the selected layer's tiles, only the ones in the visible range are laid out
+    fn draw_tile_ids(&self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let layer = match self.map.as_ref().and_then(|map| map.layers.get(self.selected_layer)) {
+            None => return,
+            Some(layer) => layer,
+        };
+
+        let tile_width = layer.tile_size.width;
+        let tile_height = layer.tile_size.height;
+        let range = self.layer_tile_range(layer, window_size);
+
+        for y in 0..range.height_tiles {
+            for x in 0..range.width_tiles {
+                let tile_x = range.tile_offset_x + x;
+                let tile_y = range.tile_offset_y + y;
+                let tile_index = match layer.tile_at(tile_x, tile_y) {
+                    None | Some(0) => continue,
+                    Some(tile_index) => tile_index,
+                };
+
+                let text = match self.layout_text(&format!("{:#x}", tile_index), TILE_ID_FONT_SIZE) {
+                    None => return,
+                    Some(text) => text,
+                };
+                let center = self.world_to_screen(Vec2::new(
+                    (tile_x as f32 + 0.5) * tile_width as f32,
+                    (tile_y as f32 + 0.5) * tile_height as f32,
+                ));
+                let position = center - Vec2::new(text.width(), text.height()) / 2.0;
+
+                // A drop shadow keeps the label readable over light and dark tiles
+                graphics.draw_text(position + Vec2::new(1.0, 1.0), Color::BLACK, &text);
+                graphics.draw_text(position, Color::WHITE, &text);
+            }
+        }
+    }
+
+    // Colour for a tile id along a hue gradient, from red for the lowest id in the map
+    // data to violet for the highest, so neighbouring offsets get similar colours
+    fn tile_id_colour(&self, tile_index: u32) -> Color {
+        let (first, last) = match (self.tile_spans.first(), self.tile_spans.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => return Color::WHITE,
+        };
+
+        let position = tile_index.saturating_sub(first) as f32 / (last - first).max(1) as f32;
+        let hue = position.clamp(0.0, 1.0) * 5.0;
+        let fraction = hue.fract();
+        let (r, g, b) = match hue as u32 {
+            0 => (1.0, fraction, 0.0),
+            1 => (1.0 - fraction, 1.0, 0.0),
+            2 => (0.0, 1.0, fraction),
+            3 => (0.0, 1.0 - fraction, 1.0),
+            4 => (fraction, 0.0, 1.0),
+            _ => (1.0, 0.0, 1.0),
+        };
+        Color::from_rgb(r, g, b)
+    }
+
+    // Covers the right half of the window with the selected layer's cells, each filled
+    // with its tile id's colour, so they can be compared against the tiles on the left
+    fn draw_split_tile_ids(&self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let layer = match self.map.as_ref().and_then(|map| map.layers.get(self.selected_layer)) {
+            None => return,
+            Some(layer) => layer,
+        };
+
+        let split = (window_size.x / 2) as f32;
+        let window_bottom_right = window_size.into_f32();
+        graphics.draw_rectangle(Rectangle::new(Vec2::new(split, 0.0), window_bottom_right), Color::BLACK);
+
+        let tile_size = Vec2::new(layer.tile_size.width as f32, layer.tile_size.height as f32);
+        let range = self.layer_tile_range(layer, window_size);
+        for y in 0..range.height_tiles {
+            for x in 0..range.width_tiles {
+                let tile_x = range.tile_offset_x + x;
+                let tile_y = range.tile_offset_y + y;
+                let tile_index = match layer.tile_at(tile_x, tile_y) {
+                    None | Some(0) => continue,
+                    Some(tile_index) => tile_index,
+                };
+
+                let top_left = Vec2::new(tile_x as f32 * tile_size.x, tile_y as f32 * tile_size.y);
+                let cell = self.world_rect_to_screen(top_left, tile_size);
+                if cell.bottom_right().x <= split {
+                    continue;
+                }
+                // Cells crossing the split are cut at it
+                let clipped_top_left = Vec2::new(cell.top_left().x.max(split), cell.top_left().y);
+                let colour = self.tile_id_colour(tile_index);
+                graphics.draw_rectangle(Rectangle::new(clipped_top_left, *cell.bottom_right()), colour);
+            }
+        }
+
+        graphics.draw_line(Vec2::new(split, 0.0), Vec2::new(split, window_bottom_right.y), 2.0, Color::WHITE);
+    }
 
-use crate::map::{load_map, Map};
+    // Draws the range of the map data holding tiles as a bar, lit where a tile is
+    // stored and dark where the data holds none
+    fn draw_tile_spans(&self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let (first, last) = match (self.tile_spans.first(), self.tile_spans.last()) {
+            (Some(first), Some(last)) => (first.0 as u64, last.0 as u64 + last.1 as u64),
+            _ => return,
+        };
 
-pub struct MapView {
-    tiles: HashMap<u32, ImageHandle>,
-    images_loaded: bool,
-    map: Option<Map>,
-    pan_up: bool,
-    pan_down: bool,
-    pan_left: bool,
-    pan_right: bool,
-    offset_x: u32,
-    offset_y: u32,
-    font: Font,
-    event_sender: UserEventSender<MapViewEvent>
-}
+        let margin = 16.0 * self.scale_factor;
+        let top = margin;
+        let bottom = top + TILE_SPAN_BAR_HEIGHT * self.scale_factor;
+        let width = window_size.x as f32 - 2.0 * margin;
+        let x_of = |offset: u64| margin + (offset - first) as f32 / (last - first).max(1) as f32 * width;
 
-#[derive(Debug)]
-pub enum MapViewEvent {
-    OpenMap
-}
+        graphics.draw_rectangle(
+            Rectangle::new(Vec2::new(margin, top), Vec2::new(margin + width, bottom)),
+            Color::from_rgb(0.35, 0.05, 0.05),
+        );
 
-impl MapView {
-    pub fn new(font: Font, event_sender: UserEventSender<MapViewEvent>) -> MapView {
-        MapView {
-            tiles: Default::default(),
+        // Adjacent tiles are merged into runs, which also adds up the unused bytes
+        let mut unused = 0u64;
+        let mut run: Option<(u64, u64)> = None;
+        let mut runs = Vec::<(u64, u64)>::new();
+        for (tile_id, length) in &self.tile_spans {
+            let (start, end) = (*tile_id as u64, *tile_id as u64 + *length as u64);
+            run = match run {
+                Some((run_start, run_end)) if start <= run_end => Some((run_start, run_end.max(end))),
+                Some((run_start, run_end)) => {
+                    unused += start - run_end;
+                    runs.push((run_start, run_end));
+                    Some((start, end))
+                }
+                None => Some((start, end)),
+            };
+        }
+        runs.extend(run);
 
-            images_loaded: false,
-            map: None,
-            pan_up: false,
-            pan_down: false,
-            pan_left: false,
-            pan_right: false,
-            offset_x: 0,
-            offset_y: 0,
-            font,
-            event_sender
+        for (start, end) in runs {
+            // At least a pixel wide, so lone tiles in a large file stay visible
+            let left = x_of(start);
+            let right = x_of(end).max(left + 1.0);
+            graphics.draw_rectangle(
+                Rectangle::new(Vec2::new(left, top), Vec2::new(right, bottom)),
+                Color::from_rgb(0.4, 0.8, 0.4),
+            );
+        }
+
+        let label = format!(
+            "Tile data {:#x} to {:#x}: {} tiles, {} bytes unused ({:.1}%)",
+            first,
+            last,
+            self.tile_spans.len(),
+            unused,
+            unused as f64 * 100.0 / (last - first).max(1) as f64
+        );
+        if let Some(text) = self.layout_text(&label, STATUS_BAR_FONT_SIZE) {
+            graphics.draw_text(Vec2::new(margin, bottom + 4.0 * self.scale_factor), Color::WHITE, &text);
         }
     }
 
-    fn on_draw_map(&mut self, helper: &mut WindowHelper<MapViewEvent>, graphics: &mut Graphics2D) {
-        let map = &mut self.map.as_ref().unwrap();
+    // Lines every grid_spacing base layer tiles, with every GRID_MAJOR_EVERY'th line
+    // thicker. Only the part of the map in view is drawn
+    fn draw_grid(&self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let (map, spacing) = match (&self.map, self.preferences.grid_spacing) {
+            (Some(map), Some(spacing)) => (map, spacing),
+            _ => return,
+        };
+
+        let tile_size = map.layers[0].tile_size;
+        let map_pixels = map.pixel_size();
+        let minor_colour = Color::from_rgba(1.0, 1.0, 1.0, 0.15);
+        let major_colour = Color::from_rgba(1.0, 1.0, 1.0, 0.4);
 
-        if !self.images_loaded {
-            for l in 0..map.layers.len() {
-                for index in map.layers[l].tiles.keys() {
-                    let data = &map.layers[l].tiles.get(index).unwrap().pixels;
-                    let tile = graphics
-                        .create_image_from_raw_pixels(
-                            ImageDataType::RGBA,
-                            ImageSmoothingMode::NearestNeighbor,
-                            (32, 32),
-                            data.as_slice(),
-                        )
-                        .unwrap();
-                    self.tiles.insert(*index, tile);
+        // The corners swap when the view is flipped
+        let a = self.screen_to_world(Vec2::ZERO);
+        let b = self.screen_to_world(window_size.into_f32());
+        let visible_min = Vec2::new(a.x.min(b.x).max(0.0), a.y.min(b.y).max(0.0));
+        let visible_max = Vec2::new(
+            a.x.max(b.x).min(map_pixels.width as f32),
+            a.y.max(b.y).min(map_pixels.height as f32),
+        );
+
+        // Vertical lines, then horizontal ones. The step is worked out in f32 as --grid
+        // takes any spacing, which can overflow a u32 once multiplied by the tile size
+        for vertical in [true, false] {
+            let (step, first, last) = if vertical {
+                (spacing as f32 * tile_size.width as f32, visible_min.x, visible_max.x)
+            } else {
+                (spacing as f32 * tile_size.height as f32, visible_min.y, visible_max.y)
+            };
+
+            let draw_minor = step * self.zoom >= MIN_GRID_LINE_GAP;
+            for line in (first / step).ceil() as u32..=(last / step).floor() as u32 {
+                let major = line % GRID_MAJOR_EVERY == 0;
+                if !major && !draw_minor {
+                    continue;
                 }
+
+                let world = line as f32 * step;
+                let (start, end) = if vertical {
+                    (Vec2::new(world, visible_min.y), Vec2::new(world, visible_max.y))
+                } else {
+                    (Vec2::new(visible_min.x, world), Vec2::new(visible_max.x, world))
+                };
+                let (width, colour) = if major { (2.0, major_colour) } else { (1.0, minor_colour) };
+                graphics.draw_line(self.world_to_screen(start), self.world_to_screen(end), width, colour);
             }
+        }
+    }
+
+    fn clear_area(&mut self) {
+        self.area_points.clear();
+        self.area_closed = false;
+    }
 
-            self.images_loaded = true;
+    fn add_area_point(&mut self) {
+        if self.area_closed {
+            self.clear_area();
         }
+        self.area_points.push(self.screen_to_world(self.mouse_position));
+    }
 
-        let mut require_redraw = false;
+    // Enclosed area in base layer tiles, once the polygon has been closed
+    fn measured_area(&self) -> Option<f32> {
+        let tile_size = match &self.map {
+            Some(map) if self.area_closed => map.layers[0].tile_size,
+            _ => return None,
+        };
+        Some(polygon_area(&self.area_points) / (tile_size.width * tile_size.height) as f32)
+    }
 
-        let window_size = helper.get_size_pixels();
+    fn close_area(&mut self, helper: &mut WindowHelper<MapViewEvent>) {
+        if self.area_closed || self.area_points.len() < 3 {
+            self.show_notice(helper, "Shift+click at least three points to measure an area".to_string());
+            return;
+        }
 
-        let tile_width = map.layers[0].tile_width;
-        let tile_height = map.layers[0].tile_height;
+        self.area_closed = true;
+        if let Some(area) = self.measured_area() {
+            self.show_notice(helper, format!("Area: {:.1} tiles", area));
+        }
+    }
 
-        let map_width_pixels = map.layers[0].map_width * tile_width;
-        let map_height_pixels = map.layers[0].map_height * tile_height;
+    fn draw_area(&self, graphics: &mut Graphics2D) {
+        let colour = Color::from_rgb(0.2, 0.8, 1.0);
+        let corners: Vec<Vec2> = self.area_points.iter().map(|point| self.world_to_screen(*point)).collect();
 
-        // TODO: probably need to figure out the panning speed based on framerate
-        let pan_speed = 16;
-        if self.pan_up && self.offset_y > pan_speed {
-            self.offset_y = self.offset_y - pan_speed;
-            require_redraw = true;
+        for corner in &corners {
+            graphics.draw_circle(*corner, 4.0, colour);
+        }
+        for edge in corners.windows(2) {
+            graphics.draw_line(edge[0], edge[1], 2.0, colour);
         }
 
-        if self.pan_down && (self.offset_y + window_size.y < map_height_pixels) {
-            self.offset_y = self.offset_y + pan_speed;
-            require_redraw = true;
+        let area = match self.measured_area() {
+            None => return,
+            Some(area) => area,
+        };
+        if let (Some(first), Some(last)) = (corners.first(), corners.last()) {
+            graphics.draw_line(*last, *first, 2.0, colour);
         }
 
-        if self.pan_left && self.offset_x > pan_speed {
-            self.offset_x = self.offset_x - pan_speed;
-            require_redraw = true;
+        // Labelled at the average of the corners, which is inside most shapes people draw
+        let center = corners.iter().fold(Vec2::ZERO, |sum, corner| sum + *corner) / corners.len() as f32;
+        if let Some(message) = self.layout_text(&format!("{:.1} tiles", area), 18.0) {
+            graphics.draw_text(center - message.size() / 2.0, colour, &message);
         }
+    }
+
+    // Outlines each layer in its tint colour, with its number at the top left corner
+    fn draw_layer_extents(&self, graphics: &mut Graphics2D) {
+        let map = match &self.map {
+            None => return,
+            Some(map) => map,
+        };
 
-        if self.pan_right && (self.offset_x + window_size.x < map_width_pixels) {
-            self.offset_x = self.offset_x + pan_speed;
-            require_redraw = true;
+        for (l, layer) in map.layers.iter().enumerate() {
+            let (r, g, b) = LAYER_TINTS[l % LAYER_TINTS.len()];
+            let colour = Color::from_rgb(r, g, b);
+            let size = layer.pixel_size();
+            let extent = self.world_rect_to_screen(Vec2::ZERO, Vec2::new(size.width as f32, size.height as f32));
+            draw_outline(graphics, *extent.top_left(), *extent.bottom_right(), colour);
+
+            let label = format!("Layer {}: {}x{} tiles", l + 1, layer.map_size.width, layer.map_size.height);
+            if let Some(text) = self.layout_text(&label, STATUS_BAR_FONT_SIZE) {
+                // Stacked, as layers that start at the same corner would overlap
+                let offset = Vec2::new(4.0, 4.0) * self.scale_factor + Vec2::new(0.0, l as f32 * text.height());
+                graphics.draw_text(*extent.top_left() + offset, colour, &text);
+            }
         }
+    }
 
-        // Calculate the starting tile
-        let tile_offset_x = self.offset_x / tile_width;
-        let tile_offset_y = self.offset_y / tile_height;
-        let pixel_offset_x = self.offset_x % tile_width;
-        let pixel_offset_y = self.offset_y % tile_height;
+    // Shows which way the map's top (N) and right (E) edges face on screen, in the
+    // bottom right corner above the status bar
+    fn draw_compass(&self, graphics: &mut Graphics2D, window_size: UVec2) {
+        let radius = COMPASS_RADIUS * self.scale_factor;
+        let margin = 8.0 * self.scale_factor;
+        let center = Vec2::new(
+            window_size.x as f32 - margin - radius,
+            window_size.y as f32 - STATUS_BAR_HEIGHT * self.scale_factor - margin - radius,
+        );
+        graphics.draw_circle(center, radius, Color::from_rgba(0.0, 0.0, 0.0, 0.6));
 
-        // Calculate the screen width in tiles
-        let screen_width_tiles = std::cmp::min(window_size.x / tile_width, map.layers[0].map_width);
-        let screen_width_tiles = if pixel_offset_x > 0
-            && (tile_offset_x + screen_width_tiles < map.layers[0].map_width)
-        {
-            screen_width_tiles + 1
-        } else {
-            screen_width_tiles
+        let north = Vec2::new(0.0, if self.flip_v { 1.0 } else { -1.0 }) * radius;
+        let east = Vec2::new(if self.flip_h { -1.0 } else { 1.0 }, 0.0) * radius;
+        graphics.draw_line(center - east * 0.5, center + east * 0.5, self.scale_factor, Color::WHITE);
+        graphics.draw_line(center - north * 0.5, center, self.scale_factor, Color::WHITE);
+        graphics.draw_line(center, center + north * 0.5, 3.0 * self.scale_factor, Color::RED);
+
+        for (label, direction, colour) in [("N", north, Color::RED), ("E", east, Color::WHITE)] {
+            if let Some(text) = self.layout_text(label, TILE_ID_FONT_SIZE) {
+                let size = Vec2::new(text.width(), text.height());
+                graphics.draw_text(center + direction * 0.75 - size / 2.0, colour, &text);
+            }
+        }
+    }
+
+    fn draw_measurement(&self, graphics: &mut Graphics2D, tile_size: Dimensions) {
+        let colour = Color::from_rgb(1.0, 0.2, 0.2);
+
+        for point in &self.measure_points {
+            graphics.draw_circle(self.world_to_screen(*point), 4.0, colour);
+        }
+
+        if let [start, end] = self.measure_points[..] {
+            let delta = end - start;
+            let tiles = Vec2::new(delta.x / tile_size.width as f32, delta.y / tile_size.height as f32);
+
+            let label_position = self.world_to_screen(end) + Vec2::new(8.0, 8.0) * self.scale_factor;
+            let label = format!(
+                "{:.1} px / {:.1} tiles\nManhattan: {:.0} px / {:.1} tiles",
+                delta.magnitude(),
+                tiles.magnitude(),
+                delta.x.abs() + delta.y.abs(),
+                tiles.x.abs() + tiles.y.abs(),
+            );
+            graphics.draw_line(self.world_to_screen(start), self.world_to_screen(end), 2.0, colour);
+            if let Some(message) = self.layout_text(&label, 18.0) {
+                graphics.draw_text(label_position, colour, &message);
+            }
+        }
+    }
+
+    fn load_images(&mut self, graphics: &mut Graphics2D) {
+        let map = match &self.map {
+            Some(map) if !self.images_loaded && !self.placeholder_tiles => map,
+            _ => return,
         };
 
-        let screen_height_tiles = std::cmp::min(window_size.y / tile_height, map.layers[0].map_height);
-        let screen_height_tiles = if pixel_offset_y > 0
-            && (tile_offset_y + screen_height_tiles < map.layers[0].map_height)
-        {
-            screen_height_tiles + 1
-        } else {
-            screen_height_tiles
+        self.tiles.clear();
+        self.failed_images.clear();
+        for (l, index, tile) in map.iter_tiles() {
+            let layer = &map.layers[l];
+            let image = graphics.create_image_from_raw_pixels(
+                ImageDataType::RGBA,
+                ImageSmoothingMode::NearestNeighbor,
+                (layer.tile_size.width, layer.tile_size.height),
+                tile.pixels.as_slice(),
+            );
+
+            // One failed upload, e.g. on a flaky driver, shouldn't take the rest of the map with it
+            match image {
+                Ok(image) => {
+                    self.tiles.insert((l, index), image);
+                }
+                Err(e) => {
+                    warn!("Failed to create image for tile {:#x} in layer {}: {}", index, l, e);
+                    self.failed_images.insert((l, index));
+                }
+            }
+        }
+
+        // Uploads that fail are usually a lost GL context, after sleep and resume or a
+        // driver reset, which also leaves the images that did upload before unusable. The
+        // pixels are kept with the map, so every image is rebuilt on the next frame. A
+        // few tries in a row are allowed, so a tile that can never upload doesn't loop
+        if self.failed_images.is_empty() {
+            self.image_rebuilds = 0;
+        } else if self.image_rebuilds < MAX_IMAGE_REBUILDS {
+            self.image_rebuilds += 1;
+            warn!(
+                "{} tile images failed to upload, rebuilding all of them (attempt {} of {})",
+                self.failed_images.len(),
+                self.image_rebuilds,
+                MAX_IMAGE_REBUILDS
+            );
+            self.dirty = true;
+            return;
+        }
+
+        self.images_loaded = true;
+    }
+
+    fn print_selected_tile(&self) {
+        let (map, browser) = match (&self.map, &self.browser) {
+            (Some(map), Some(browser)) => (map, browser),
+            _ => return,
         };
 
+        if let Some(tile_id) = browser.selected_tile() {
+            if let Some(tile) = map.layers.iter().find_map(|layer| layer.tiles.get(&tile_id)) {
+                println!("{}", describe_tile(tile_id, tile));
+            }
+        }
+    }
+
+    fn on_key_down_browser(&mut self, helper: &mut WindowHelper<MapViewEvent>, key: VirtualKeyCode) {
+        let window_size = helper.get_size_pixels();
+        let browser = match &mut self.browser {
+            None => return,
+            Some(browser) => browser,
+        };
+
+        match key {
+            VirtualKeyCode::Up => browser.move_selection(0, -1, window_size),
+            VirtualKeyCode::Down => browser.move_selection(0, 1, window_size),
+            VirtualKeyCode::Left => browser.move_selection(-1, 0, window_size),
+            VirtualKeyCode::Right => browser.move_selection(1, 0, window_size),
+            VirtualKeyCode::Return => self.print_selected_tile(),
+            VirtualKeyCode::Space => {
+                self.brush = browser.selected_tile();
+                self.browser = None;
+                if let Some(tile_id) = self.brush {
+                    self.show_notice(helper, format!("Brush: tile {:#x}, Ctrl+click to paint", tile_id));
+                }
+            }
+            VirtualKeyCode::B | VirtualKeyCode::Escape => self.browser = None,
+            _ => return,
+        }
+        self.invalidate(helper);
+    }
+
+    fn on_draw_map(&mut self, helper: &mut WindowHelper<MapViewEvent>, graphics: &mut Graphics2D) {
+        self.load_images(graphics);
+
+        if let Some(browser) = &self.browser {
+            browser.draw(graphics, helper.get_size_pixels(), &self.tiles);
+            // Shows the usage count of a clicked tile
+            self.draw_notice(graphics, helper.get_size_pixels());
+            return;
+        }
+
+        let window_size = helper.get_size_pixels();
+        if let Some(view) = self.pending_view.take() {
+            self.apply_view_state(view, window_size);
+        }
+
+        let map = self.map.as_ref().unwrap();
+
+        // Measurements and the status bar count in base layer tiles
+        let base_tile_size = map.layers[0].tile_size;
+
+        let map_pixels = map.pixel_size();
+
+        // TODO: probably need to figure out the panning speed based on framerate
+        let pan_speed = self.pan_speed;
+        if self.is_panning() {
+            let (min_x, max_x) = self.offset_limits(window_size.x, map_pixels.width);
+            let (min_y, max_y) = self.offset_limits(window_size.y, map_pixels.height);
+            let delta_x = (self.pan_right as i32 - self.pan_left as i32) as f32 * pan_speed / self.zoom;
+            let delta_y = (self.pan_down as i32 - self.pan_up as i32) as f32 * pan_speed / self.zoom;
+
+            let offset = (
+                (self.offset_x + delta_x).clamp(min_x, max_x),
+                (self.offset_y + delta_y).clamp(min_y, max_y),
+            );
+            if offset != (self.offset_x, self.offset_y) {
+                (self.offset_x, self.offset_y) = offset;
+                self.dirty = true;
+            }
+        }
+
         graphics.clear_screen(Color::BLACK);
 
-        for y in 0..screen_height_tiles {
-            for x in 0..screen_width_tiles {
-                for l in 0..map.layers.len() {
-                    let tile_x = tile_offset_x + x;
-                    let tile_y = tile_offset_y + y;
+        let image_coords = self.tile_image_coords();
+        let empty_colour = match self.current_palette() {
+            Some(palette) if self.preferences.opaque_index_zero => {
+                palette.first().map(|colour| Color::from_int_rgb(colour.r, colour.g, colour.b))
+            }
+            _ => None,
+        };
+
+        // Each layer is culled against its own grid, as layers can differ in tile size.
+        // Drawing a whole layer before the next gives the same result as drawing cell by cell
+        for (l, layer) in map.layers.iter().enumerate() {
+            let opacity = self.layer_opacity.get(l).copied().unwrap_or(1.0);
+            if opacity <= 0.0 {
+                continue;
+            }
 
-                    let position = (tile_x + (tile_y * map.layers[l].map_width)) as usize;
-                    let tile_index = map.layers[l].tile_map[position];
+            let tile_width = layer.tile_size.width;
+            let tile_height = layer.tile_size.height;
+            let range = self.layer_tile_range(layer, window_size);
 
-                    let tile_width = map.layers[l].tile_width;
-                    let tile_height = map.layers[l].tile_height;
+            for y in 0..range.height_tiles {
+                for x in 0..range.width_tiles {
+                    let tile_x = range.tile_offset_x + x;
+                    let tile_y = range.tile_offset_y + y;
+                    let rectangle = self.world_rect_to_screen(
+                        Vec2::new(tile_x as f32 * tile_width as f32, tile_y as f32 * tile_height as f32),
+                        Vec2::new(tile_width as f32, tile_height as f32),
+                    );
 
-                    if tile_index == 0 {
-                        continue;
-                    }
+                    let tile_index = match layer.tile_at(tile_x, tile_y) {
+                        None => continue,
+                        Some(0) => {
+                            // Only the base layer is filled, so the layers above don't hide it
+                            if let (0, Some(colour)) = (l, empty_colour) {
+                                graphics.draw_rectangle(rectangle, colour);
+                            }
+                            continue;
+                        }
+                        Some(tile_index) => tile_index,
+                    };
 
-                    if let Some(tile) = self.tiles.get(&tile_index) {
-                        graphics.draw_image(
-                            (
-                                (x * tile_width) as f32 - pixel_offset_x as f32,
-                                (y * tile_height) as f32 - pixel_offset_y as f32,
-                            ),
-                            tile,
-                        );
+                    if let Some(tile) = self.tiles.get(&(l, tile_index)) {
+                        let colour = self.layer_colour(l, opacity);
+                        graphics.draw_rectangle_image_subset_tinted(rectangle, colour, &image_coords, tile);
+                    } else if self.layout_only {
+                        graphics.draw_rectangle(rectangle, layout_colour(tile_index, opacity));
+                    } else if self.placeholder_tiles
+                        || self.failed_images.contains(&(l, tile_index))
+                        || layer.failed_tiles.contains_key(&tile_index)
+                    {
+                        let shade = 0.2 + ((tile_index >> 2) % 8) as f32 / 12.0;
+                        graphics.draw_rectangle(rectangle, Color::from_gray(shade));
                     }
                 }
             }
         }
 
-        if require_redraw {
-            helper.request_redraw();
+        if self.preferences.split_tile_ids {
+            self.draw_split_tile_ids(graphics, window_size);
+        }
+
+        self.draw_grid(graphics, window_size);
+
+        if self.preferences.show_layer_extents {
+            self.draw_layer_extents(graphics);
+        }
+
+        if self.preferences.show_tile_ids {
+            self.draw_tile_ids(graphics, window_size);
+        }
+
+        self.draw_measurement(graphics, base_tile_size);
+        self.draw_area(graphics);
+
+        if self.preferences.show_tile_spans {
+            self.draw_tile_spans(graphics, window_size);
+        }
+
+        let center = self.screen_to_world(window_size.into_f32() / 2.0);
+        let mut center_tile = (
+            (center.x / base_tile_size.width as f32).floor() as i32,
+            (center.y / base_tile_size.height as f32).floor() as i32,
+        );
+        if self.origin_bottom_left {
+            center_tile.1 = map.layers[0].map_size.height as i32 - 1 - center_tile.1;
+        }
+        self.draw_status_bar(graphics, window_size, center_tile);
+        if !self.preferences.hide_compass {
+            self.draw_compass(graphics, window_size);
+        }
+        self.draw_inspector(graphics);
+        self.draw_palette_editor(graphics);
+        self.draw_notice(graphics, window_size);
+    }
+
+    fn layer_status(&self) -> String {
+        let mut status = Vec::<String>::new();
+        for (l, opacity) in self.layer_opacity.iter().enumerate() {
+            let mut layer = format!("{}", l + 1);
+            if *opacity < 1.0 {
+                layer.push_str(&format!(" {:.0}%", opacity * 100.0));
+            }
+            if l == self.selected_layer {
+                layer = format!("[{}]", layer);
+            }
+            status.push(layer);
+        }
+        let mut status = status.join(" ");
+        if self.preferences.tint_layers {
+            status.push_str(" (tinted)");
         }
+        status
+    }
+
+    fn draw_status_bar(&self, graphics: &mut Graphics2D, window_size: UVec2, center_tile: (i32, i32)) {
+        let bar_height = STATUS_BAR_HEIGHT * self.scale_factor;
+        let top = window_size.y as f32 - bar_height;
+        graphics.draw_rectangle(
+            Rectangle::new(Vec2::new(0.0, top), Vec2::new(window_size.x as f32, window_size.y as f32)),
+            Color::from_rgba(0.0, 0.0, 0.0, 0.75),
+        );
+
+        let file_name = self.map_source.as_ref().map(MapSource::name).unwrap_or_default();
+
+        let status = format!(
+            "{}    Center: {}, {}    Zoom: {:.0}%    Layers: {}    Index 0: {}{}",
+            file_name,
+            center_tile.0,
+            center_tile.1,
+            self.zoom * 100.0,
+            self.layer_status(),
+            if self.preferences.opaque_index_zero { "opaque" } else { "transparent" },
+            self.brush.map(|tile_id| format!("    Brush: {:#x}", tile_id)).unwrap_or_default()
+        );
+        let message = match self.layout_text(&status, STATUS_BAR_FONT_SIZE) {
+            None => return,
+            Some(message) => message,
+        };
+        let text_top = top + (bar_height - message.height()) / 2.0;
+        graphics.draw_text((8.0 * self.scale_factor, text_top), Color::WHITE, &message);
+
+        self.draw_pixel_readout(graphics, window_size, top);
+    }
+
+    // Swatch and hex RGBA value of the pixel under the cursor, at the right of the status bar
+    fn draw_pixel_readout(&self, graphics: &mut Graphics2D, window_size: UVec2, top: f32) {
+        let (r, g, b, a) = match self.pixel_at_screen(self.mouse_position) {
+            None => return,
+            Some(pixel) => pixel,
+        };
+
+        let text = match self.layout_text(&format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a), STATUS_BAR_FONT_SIZE) {
+            None => return,
+            Some(text) => text,
+        };
+
+        let bar_height = STATUS_BAR_HEIGHT * self.scale_factor;
+        let margin = 8.0 * self.scale_factor;
+        let swatch_size = bar_height - 2.0 * 4.0 * self.scale_factor;
+        let text_left = window_size.x as f32 - margin - text.width();
+        let swatch_top_left = Vec2::new(text_left - margin - swatch_size, top + (bar_height - swatch_size) / 2.0);
+
+        graphics.draw_rectangle(
+            Rectangle::new(swatch_top_left, swatch_top_left + Vec2::new(swatch_size, swatch_size)),
+            Color::from_int_rgba(r, g, b, a),
+        );
+        draw_outline(
+            graphics,
+            swatch_top_left,
+            swatch_top_left + Vec2::new(swatch_size, swatch_size),
+            Color::from_gray(0.6),
+        );
+        graphics.draw_text((text_left, top + (bar_height - text.height()) / 2.0), Color::WHITE, &text);
     }
 
     fn on_draw_no_map(&mut self, _helper: &mut WindowHelper<MapViewEvent>, graphics: &mut Graphics2D) {
         graphics.clear_screen(Color::from_rgb(0.8, 0.8, 0.8));
-        let message = self.font.layout_text("KKnD 2 Map Viewer\nPress 'O' to open a map file\n\nSupports KKnD 2 LPS, LPC, LPM, and extracted MAPD files", 32.0, TextOptions::new());
-        graphics.draw_text((50.0, 50.0), Color::BLACK, &message);
+        let help = format!(
+            "KKnD 2 Map Viewer\nPress 'O' to open a map file\nPress 'D' to browse a directory of maps\n\nSupports KKnD 2 {} files",
+            supported_formats()
+        );
+        let message = match self.layout_text(&help, 32.0) {
+            None => return,
+            Some(message) => message,
+        };
+        let margin = 50.0 * self.scale_factor;
+        graphics.draw_text((margin, margin), Color::BLACK, &message);
     }
 }
 
 impl WindowHandler<MapViewEvent> for MapView {
-    fn on_user_event(&mut self, _helper: &mut WindowHelper<MapViewEvent>, event: MapViewEvent) {
+    fn on_start(&mut self, helper: &mut WindowHelper<MapViewEvent>, info: WindowStartupInfo) {
+        self.scale_factor = info.scale_factor() as f32;
+        // A map may have been opened from the command line before the window started
+        self.update_title(helper);
+    }
+
+    // The offset limits depend on the window size, e.g. after toggling fullscreen
+    fn on_resize(&mut self, helper: &mut WindowHelper<MapViewEvent>, size_pixels: UVec2) {
+        self.clamp_offset(size_pixels);
+        self.invalidate(helper);
+    }
+
+    fn on_scale_factor_changed(&mut self, helper: &mut WindowHelper<MapViewEvent>, scale_factor: f64) {
+        self.scale_factor = scale_factor as f32;
+        self.invalidate(helper);
+    }
+
+    fn on_user_event(&mut self, helper: &mut WindowHelper<MapViewEvent>, event: MapViewEvent) {
+        let opens_dialog = matches!(
+            event,
+            MapViewEvent::OpenMap | MapViewEvent::ExportPalette | MapViewEvent::SaveMap | MapViewEvent::OpenDirectory
+        );
+
         match event {
             MapViewEvent::OpenMap => {
-                let path = env::current_dir().unwrap();
-                let file = FileDialog::new()
-                    .add_filter("Level Archives", &["lps", "lpc", "lpm", "MAPD"])
-                    .set_directory(path)
-                    .pick_file();
+                let mut dialog = FileDialog::new()
+                    .add_filter("Level Archives", SUPPORTED_EXTENSIONS);
+                if let Some(directory) = dialog_directory() {
+                    dialog = dialog.set_directory(directory);
+                }
+                let file = dialog.pick_file();
 
                 if let Some(path) = file {
-                    self.map = Option::from(load_map(&path).unwrap());
-                    self.tiles.clear();
-                    self.images_loaded = false;
+                    self.open_map_with_title(helper, MapSource::File(path));
+                    self.invalidate(helper);
+                }
+            }
+            MapViewEvent::ExportPalette => {
+                // Includes any changes made in the palette editor
+                let palette = match self.current_palette() {
+                    None => return,
+                    Some(palette) => palette,
+                };
+
+                let mut dialog = FileDialog::new()
+                    .add_filter("Adobe Color Table", &["act"])
+                    .add_filter("JASC Palette", &["pal"]);
+                if let Some(directory) = dialog_directory() {
+                    dialog = dialog.set_directory(directory);
+                }
+
+                if let Some(path) = dialog.save_file() {
+                    match save_palette(&path, &palette) {
+                        Ok(()) => self.show_notice(helper, format!("Exported palette to {}", path.display())),
+                        Err(e) => error!("{}", e),
+                    }
+                }
+            }
+            MapViewEvent::SaveMap => {
+                // The tiles were never read, so they would be saved as zeros
+                if self.layout_only {
+                    self.show_notice(helper, "Can't save in layout only mode, 'Shift+F5' loads the tiles".to_string());
+                    return;
+                }
+
+                let palette = match self.current_palette() {
+                    None => return,
+                    Some(palette) => palette,
+                };
+
+                let mut dialog = FileDialog::new().add_filter("MAPD", &["MAPD"]);
+                if let Some(directory) = dialog_directory() {
+                    dialog = dialog.set_directory(directory);
+                }
+
+                if let (Some(path), Some(map)) = (dialog.save_file(), &self.map) {
+                    match save_map(&path, map, &palette) {
+                        Ok(()) => self.show_notice(helper, format!("Saved map to {}", path.display())),
+                        Err(e) => error!("{}", e),
+                    }
+                }
+            }
+            MapViewEvent::OpenDirectory => {
+                let mut dialog = FileDialog::new();
+                let directory = match &self.directory_browser {
+                    Some(browser) => Some(browser.directory().to_path_buf()),
+                    None => dialog_directory(),
+                };
+                if let Some(directory) = directory {
+                    dialog = dialog.set_directory(directory);
+                }
+
+                if let Some(directory) = dialog.pick_folder() {
+                    self.open_directory(&directory);
+                    self.invalidate(helper);
+                }
+            }
+            MapViewEvent::ThumbnailRendered(path, thumbnail) => {
+                if let Some(browser) = &mut self.directory_browser {
+                    browser.thumbnail_rendered(path, thumbnail);
+                    if self.show_directory {
+                        self.invalidate(helper);
+                    }
+                }
+            }
+            MapViewEvent::Reload => {
+                if let Some(source) = self.map_source.clone() {
+                    // Reloading the same map keeps the view where it was
+                    let view = (self.offset_x, self.offset_y, self.zoom, self.flip_h, self.flip_v);
+                    self.open_map_with_title(helper, source);
+                    (self.offset_x, self.offset_y, self.zoom, self.flip_h, self.flip_v) = view;
+                    self.invalidate(helper);
                 }
             }
         }
+
+        if opens_dialog {
+            self.release_keys();
+        }
     }
 
     fn on_draw(&mut self, helper: &mut WindowHelper<MapViewEvent>, graphics: &mut Graphics2D) {
-        match self.map {
-            None => self.on_draw_no_map(helper, graphics),
-            Some(_) => self.on_draw_map(helper, graphics),
+        // Drawing may mark the view dirty again, e.g. while a pan key is held
+        self.dirty = false;
+
+        if self.show_directory {
+            self.draw_directory_browser(graphics, helper.get_size_pixels());
+        } else {
+            match self.map {
+                None => self.on_draw_no_map(helper, graphics),
+                Some(_) => self.on_draw_map(helper, graphics),
+            }
+        }
+
+        if self.dirty {
+            helper.request_redraw();
+        }
+    }
+
+    fn on_mouse_move(&mut self, helper: &mut WindowHelper<MapViewEvent>, position: Vec2) {
+        self.mouse_position = position;
+
+        if self.painting {
+            self.paint_at_screen(helper, position);
+        }
+
+        if self.map.is_some() && self.browser.is_none() && !self.show_directory {
+            let pixel = self.pixel_at_screen(position);
+            if pixel != self.hovered_pixel {
+                self.hovered_pixel = pixel;
+                self.invalidate(helper);
+            }
+        }
+    }
+
+    // Zooms about the cursor, so the point being looked at stays under it
+    fn on_mouse_wheel_scroll(&mut self, helper: &mut WindowHelper<MapViewEvent>, distance: MouseScrollDistance) {
+        if self.map.is_none() || self.browser.is_some() || self.show_directory {
+            return;
+        }
+
+        // Mouse wheels zoom and touchpads pan, Ctrl switches a touchpad to zooming (which
+        // is also how pinch gestures arrive) and Shift switches a wheel to panning
+        let (x, y, pan) = match distance {
+            // A wheel only scrolls vertically, so with Shift held it scrolls sideways
+            MouseScrollDistance::Lines { x, y, .. } if self.modifiers.shift() && x == 0.0 => (y as f32, 0.0, true),
+            MouseScrollDistance::Lines { x, y, .. } => (x as f32, y as f32, self.modifiers.shift()),
+            MouseScrollDistance::Pixels { x, y, .. } => (
+                x as f32 / PIXELS_PER_SCROLL_LINE,
+                y as f32 / PIXELS_PER_SCROLL_LINE,
+                !self.modifiers.ctrl(),
+            ),
+            MouseScrollDistance::Pages { .. } => return,
+        };
+
+        if !pan {
+            self.zoom_at(helper, self.zoom_step.powf(y), self.mouse_position);
+            return;
+        }
+
+        self.pan_by(helper, Vec2::new(-x, -y) * PIXELS_PER_SCROLL_LINE);
+    }
+
+    fn on_mouse_button_up(&mut self, _helper: &mut WindowHelper<MapViewEvent>, button: MouseButton) {
+        if button == MouseButton::Left {
+            self.painting = false;
+            self.history.end_stroke();
+        }
+    }
+
+    fn on_mouse_button_down(&mut self, helper: &mut WindowHelper<MapViewEvent>, button: MouseButton) {
+        if button != MouseButton::Left {
+            return;
+        }
+
+        if let (Some(browser), true) = (&mut self.directory_browser, self.show_directory) {
+            if let Some(index) = browser.hit_test(self.mouse_position, helper.get_size_pixels(), self.scale_factor) {
+                browser.select(index);
+                self.invalidate(helper);
+            }
+            return;
+        }
+
+        if self.map.is_none() {
+            return;
+        }
+
+        if let Some(browser) = &mut self.browser {
+            let index = match browser.hit_test(self.mouse_position, helper.get_size_pixels()) {
+                None => return,
+                Some(index) => index,
+            };
+
+            browser.select(index);
+            if let Some(tile_id) = browser.selected_tile() {
+                let message = format!("Tile {:#x}: used {} times in layer 1", tile_id, browser.selected_usage());
+                self.show_notice(helper, message);
+            }
+            return;
+        }
+
+        if let Some(editor) = &mut self.palette_editor {
+            if let Some(index) = editor.hit_test(self.mouse_position, self.scale_factor) {
+                editor.select(index);
+                self.invalidate(helper);
+                return;
+            }
         }
+
+        if let (Some(_), true) = (self.brush, self.modifiers.ctrl()) {
+            self.painting = true;
+            self.paint_at_screen(helper, self.mouse_position);
+            return;
+        }
+
+        if self.modifiers.shift() {
+            self.add_area_point();
+            self.invalidate(helper);
+            return;
+        }
+
+        // A third click clears the previous measurement
+        if self.measure_points.len() == 2 {
+            self.measure_points.clear();
+        } else {
+            self.measure_points.push(self.screen_to_world(self.mouse_position));
+        }
+        self.invalidate(helper);
     }
 
     fn on_key_down(
@@ -206,6 +2282,32 @@ impl WindowHandler<MapViewEvent> for MapView {
         virtual_key_code: Option<VirtualKeyCode>,
         _scancode: KeyScancode,
     ) {
+        if let Some(key) = virtual_key_code {
+            let now = Instant::now();
+            let repeated = self
+                .held_keys
+                .insert(key, now)
+                .is_some_and(|last| now.duration_since(last) < KEY_REPEAT_TIMEOUT);
+            if repeated && !is_continuous(key) {
+                return;
+            }
+        }
+
+        if let (Some(key), true) = (virtual_key_code, self.show_directory) {
+            self.on_key_down_directory(helper, key);
+            return;
+        }
+
+        if let (Some(key), Some(_)) = (virtual_key_code, &self.browser) {
+            self.on_key_down_browser(helper, key);
+            return;
+        }
+
+        if let (Some(key), Some(_)) = (virtual_key_code, &self.palette_editor) {
+            self.on_key_down_palette_editor(helper, key);
+            return;
+        }
+
         if let Some(key) = virtual_key_code {
             match key {
                 VirtualKeyCode::Up => self.pan_up = true,
@@ -215,19 +2317,115 @@ impl WindowHandler<MapViewEvent> for MapView {
                 VirtualKeyCode::O => {
                     self.event_sender.send_event(MapViewEvent::OpenMap).unwrap();
                 }
+                VirtualKeyCode::P => self.reload_palette(helper),
+                VirtualKeyCode::D => self.toggle_directory_browser(helper),
+                VirtualKeyCode::F5 if self.modifiers.shift() => self.toggle_layout_only(helper),
+                VirtualKeyCode::F5 => {
+                    self.event_sender.send_event(MapViewEvent::Reload).unwrap();
+                }
+                VirtualKeyCode::E => {
+                    self.event_sender.send_event(MapViewEvent::ExportPalette).unwrap();
+                }
+                VirtualKeyCode::S if self.modifiers.ctrl() => {
+                    self.event_sender.send_event(MapViewEvent::SaveMap).unwrap();
+                }
+                VirtualKeyCode::V => self.print_validation(),
+                VirtualKeyCode::W => self.print_view_state(helper),
+                VirtualKeyCode::C => self.copy_tile_under_cursor(helper),
+                VirtualKeyCode::Home if self.modifiers.shift() => self.fit_to_content(helper),
+                VirtualKeyCode::Home | VirtualKeyCode::Backspace => self.reset_view(helper),
+                VirtualKeyCode::B => {
+                    if let Some(map) = &self.map {
+                        self.browser = Some(TileBrowser::new(map));
+                        self.invalidate(helper);
+                    }
+                }
+                VirtualKeyCode::K => self.toggle_transparent_colour(helper),
+                VirtualKeyCode::Z if self.modifiers.ctrl() => self.undo_edit(helper, false),
+                VirtualKeyCode::Y if self.modifiers.ctrl() => self.undo_edit(helper, true),
+                VirtualKeyCode::Z => self.toggle_opaque_index_zero(helper),
+                VirtualKeyCode::F => self.toggle_overscroll(helper),
+                VirtualKeyCode::X => self.toggle_inspector(helper),
+                VirtualKeyCode::H => self.toggle_flip(helper, self.modifiers.shift()),
+                VirtualKeyCode::L => self.toggle_palette_editor(helper),
+                VirtualKeyCode::F11 => self.toggle_fullscreen(helper),
+                VirtualKeyCode::G if self.modifiers.ctrl() => self.goto_center_of_mass(helper),
+                VirtualKeyCode::Return => self.close_area(helper),
+                VirtualKeyCode::Escape => {
+                    self.clear_area();
+                    self.brush = None;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::G => self.cycle_grid(helper),
+                VirtualKeyCode::I if self.modifiers.shift() => {
+                    self.preferences.split_tile_ids = !self.preferences.split_tile_ids;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::I => {
+                    self.preferences.show_tile_ids = !self.preferences.show_tile_ids;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::N => {
+                    self.preferences.hide_compass = !self.preferences.hide_compass;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::M => {
+                    self.preferences.show_tile_spans = !self.preferences.show_tile_spans;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::T if self.modifiers.shift() => {
+                    self.preferences.show_layer_extents = !self.preferences.show_layer_extents;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::T => {
+                    self.preferences.tint_layers = !self.preferences.tint_layers;
+                    self.invalidate(helper);
+                }
+                VirtualKeyCode::Key1 => self.select_layer(helper, 0),
+                VirtualKeyCode::Key2 => self.select_layer(helper, 1),
+                VirtualKeyCode::Key3 => self.select_layer(helper, 2),
+                VirtualKeyCode::Key4 => self.select_layer(helper, 3),
+                VirtualKeyCode::Key5 => self.select_layer(helper, 4),
+                VirtualKeyCode::Key6 => self.select_layer(helper, 5),
+                VirtualKeyCode::Key7 => self.select_layer(helper, 6),
+                VirtualKeyCode::Key8 => self.select_layer(helper, 7),
+                VirtualKeyCode::Key9 => self.select_layer(helper, 8),
+                VirtualKeyCode::Equals | VirtualKeyCode::Plus | VirtualKeyCode::NumpadAdd => {
+                    self.adjust_layer_opacity(helper, LAYER_OPACITY_STEP);
+                }
+                VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract => {
+                    self.adjust_layer_opacity(helper, -LAYER_OPACITY_STEP);
+                }
+                VirtualKeyCode::LBracket => {
+                    let step = if self.modifiers.shift() { 16 } else { 1 };
+                    self.adjust_palette_offset(helper, -step);
+                }
+                VirtualKeyCode::RBracket => {
+                    let step = if self.modifiers.shift() { 16 } else { 1 };
+                    self.adjust_palette_offset(helper, step);
+                }
                 _ => {}
             }
         }
-        helper.request_redraw();
+
+        // Panning continues from on_draw for as long as the key is held
+        if self.is_panning() {
+            self.invalidate(helper);
+        }
+    }
+
+    fn on_keyboard_modifiers_changed(&mut self, _helper: &mut WindowHelper<MapViewEvent>, state: ModifiersState) {
+        self.modifiers = state;
     }
 
     fn on_key_up(
         &mut self,
-        helper: &mut WindowHelper<MapViewEvent>,
+        _helper: &mut WindowHelper<MapViewEvent>,
         virtual_key_code: Option<VirtualKeyCode>,
         _scancode: KeyScancode,
     ) {
         if let Some(key) = virtual_key_code {
+            self.held_keys.remove(&key);
             match key {
                 VirtualKeyCode::Up => self.pan_up = false,
                 VirtualKeyCode::Down => self.pan_down = false,
@@ -236,6 +2434,5 @@ impl WindowHandler<MapViewEvent> for MapView {
                 _ => {}
             }
         }
-        helper.request_redraw();
     }
 }
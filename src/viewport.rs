@@ -0,0 +1,252 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+// A width and height, used for map sizes in tiles as well as tile and window sizes
+// in pixels, so the two axes can't be swapped by argument order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    pub const fn new(width: u32, height: u32) -> Dimensions {
+        Dimensions { width, height }
+    }
+
+    // Number of cells, as a u64 so that corrupt sizes can't overflow
+    pub fn area(self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    // Size in pixels of a grid of this many tiles, saturating rather than overflowing
+    pub fn pixel_size(self, tile_size: Dimensions) -> Dimensions {
+        Dimensions::new(
+            self.width.saturating_mul(tile_size.width),
+            self.height.saturating_mul(tile_size.height),
+        )
+    }
+
+    // The larger of each axis
+    pub fn max(self, other: Dimensions) -> Dimensions {
+        Dimensions::new(self.width.max(other.width), self.height.max(other.height))
+    }
+
+    pub fn contains(self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height
+    }
+
+    // Index of a cell in row-major order, which is how tile maps are stored
+    pub fn index_of(self, x: u32, y: u32) -> usize {
+        x as usize + y as usize * self.width as usize
+    }
+
+    // Column and row of a row-major index. A zero width is treated as one, so an
+    // empty layer can't divide by zero
+    pub fn position_of(self, index: usize) -> (u32, u32) {
+        let width = self.width.max(1) as usize;
+        ((index % width) as u32, (index / width) as u32)
+    }
+}
+
+// The block of tiles that covers the window, plus how far the first tile is
+// scrolled past the window's top left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileRange {
+    pub tile_offset_x: u32,
+    pub tile_offset_y: u32,
+    // Negative when the window is scrolled past the top or left edge of the map
+    pub pixel_offset_x: i32,
+    pub pixel_offset_y: i32,
+    pub width_tiles: u32,
+    pub height_tiles: u32,
+}
+
+// Counts the tiles needed to cover a window length, including partial tiles at
+// either end, without going past the edge of the map
+fn visible_tiles(offset: i32, window_length: u32, map_length: u32, tile_length: u32) -> (u32, i32, u32) {
+    // Before the start of the map the first tile is drawn further into the window
+    if offset < 0 {
+        let covered = (window_length as i64 + offset as i64).max(0) as u64;
+        let tiles = covered.div_ceil(tile_length as u64).min(map_length as u64) as u32;
+        return (0, offset, tiles);
+    }
+
+    let offset = offset as u32;
+    let tile_offset = (offset / tile_length).min(map_length);
+    let pixel_offset = offset % tile_length;
+    let tiles = (pixel_offset as u64 + window_length as u64).div_ceil(tile_length as u64);
+    let tiles = tiles.min((map_length - tile_offset) as u64) as u32;
+    (tile_offset, pixel_offset as i32, tiles)
+}
+
+// The offset is an (x, y) pair in pixels, window_size and tile_size are in pixels
+// and map_size in tiles. An offset past the end of the map, e.g. after switching to
+// a smaller map, gives an empty range rather than one that indexes outside of the
+// tile map
+pub fn visible_tile_range(
+    offset: (i32, i32),
+    window_size: Dimensions,
+    map_size: Dimensions,
+    tile_size: Dimensions,
+) -> TileRange {
+    let (tile_offset_x, pixel_offset_x, width_tiles) =
+        visible_tiles(offset.0, window_size.width, map_size.width, tile_size.width);
+    let (tile_offset_y, pixel_offset_y, height_tiles) =
+        visible_tiles(offset.1, window_size.height, map_size.height, tile_size.height);
+
+    TileRange {
+        tile_offset_x,
+        tile_offset_y,
+        pixel_offset_x,
+        pixel_offset_y,
+        width_tiles,
+        height_tiles,
+    }
+}
+
+// Smallest and largest view offsets along one axis, in map pixels. The map edge stays
+// flush with the window edge unless margin, in screen pixels, lets the view scroll
+// past it. The window covers less of the map the further in it is zoomed
+pub fn offset_limits(window_length: u32, map_length: u32, zoom: f32, margin: f32) -> (f32, f32) {
+    let max = (map_length as f32 - window_length as f32 / zoom).max(0.0);
+    let margin = margin / zoom;
+    (-margin, max + margin)
+}
+
+// Keeps an (x, y) view offset within offset_limits on both axes
+pub fn clamp_offset(
+    offset: (f32, f32),
+    window_size: Dimensions,
+    map_size: Dimensions,
+    zoom: f32,
+    margin: f32,
+) -> (f32, f32) {
+    let (min_x, max_x) = offset_limits(window_size.width, map_size.width, zoom, margin);
+    let (min_y, max_y) = offset_limits(window_size.height, map_size.height, zoom, margin);
+    (offset.0.clamp(min_x, max_x), offset.1.clamp(min_y, max_y))
+}
+
+// The offset along one axis that keeps the map position under anchor, in screen
+// pixels, in the same place on screen after zooming from zoom to new_zoom
+pub fn zoom_anchor_offset(offset: f32, anchor: f32, zoom: f32, new_zoom: f32) -> f32 {
+    offset + anchor / zoom - anchor / new_zoom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILE: Dimensions = Dimensions::new(32, 32);
+
+    #[test]
+    fn partial_tiles_at_both_ends_are_included() {
+        let range = visible_tile_range((10, 5), Dimensions::new(100, 50), Dimensions::new(100, 100), TILE);
+        assert_eq!(
+            range,
+            TileRange {
+                tile_offset_x: 0,
+                tile_offset_y: 0,
+                pixel_offset_x: 10,
+                pixel_offset_y: 5,
+                width_tiles: 4,
+                height_tiles: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn map_smaller_than_the_window_is_drawn_whole() {
+        let range = visible_tile_range((0, 0), Dimensions::new(1024, 768), Dimensions::new(2, 3), TILE);
+        assert_eq!((range.tile_offset_x, range.tile_offset_y), (0, 0));
+        assert_eq!((range.width_tiles, range.height_tiles), (2, 3));
+    }
+
+    #[test]
+    fn offset_at_the_end_of_the_map_stops_at_the_last_tile() {
+        // 10 tiles of 32 pixels in a 100 pixel window scroll at most 220 pixels
+        let range = visible_tile_range((220, 220), Dimensions::new(100, 100), Dimensions::new(10, 10), TILE);
+        assert_eq!((range.tile_offset_x, range.pixel_offset_x, range.width_tiles), (6, 28, 4));
+        assert_eq!(range.tile_offset_x + range.width_tiles, 10);
+    }
+
+    #[test]
+    fn offset_past_the_end_of_the_map_is_empty() {
+        let range = visible_tile_range((10_000, 320), Dimensions::new(100, 100), Dimensions::new(10, 10), TILE);
+        assert_eq!((range.tile_offset_x, range.width_tiles), (10, 0));
+        assert_eq!((range.tile_offset_y, range.height_tiles), (10, 0));
+    }
+
+    #[test]
+    fn overscrolled_offset_starts_the_map_inside_the_window() {
+        // 50 pixels before the map leaves 50 of the 100 pixel window to cover
+        let range = visible_tile_range((-50, -10), Dimensions::new(100, 100), Dimensions::new(10, 10), TILE);
+        assert_eq!((range.tile_offset_x, range.pixel_offset_x, range.width_tiles), (0, -50, 2));
+        assert_eq!((range.tile_offset_y, range.pixel_offset_y, range.height_tiles), (0, -10, 3));
+    }
+
+    #[test]
+    fn overscrolled_past_the_window_is_empty() {
+        let range = visible_tile_range((-200, 0), Dimensions::new(100, 100), Dimensions::new(10, 10), TILE);
+        assert_eq!(range.width_tiles, 0);
+    }
+
+    #[test]
+    fn overscrolled_small_map_is_limited_to_its_size() {
+        let range = visible_tile_range((-10, 0), Dimensions::new(1024, 768), Dimensions::new(2, 2), TILE);
+        assert_eq!(range.width_tiles, 2);
+    }
+
+    #[test]
+    fn offset_limits_shrink_the_window_when_zoomed_in() {
+        assert_eq!(offset_limits(500, 1000, 1.0, 0.0), (0.0, 500.0));
+        assert_eq!(offset_limits(500, 1000, 2.0, 0.0), (0.0, 750.0));
+        assert_eq!(offset_limits(500, 1000, 0.25, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn offset_limits_margin_is_in_screen_pixels() {
+        assert_eq!(offset_limits(500, 1000, 2.0, 128.0), (-64.0, 814.0));
+        assert_eq!(offset_limits(2000, 1000, 1.0, 128.0), (-128.0, 128.0));
+    }
+
+    #[test]
+    fn clamp_offset_keeps_the_map_edges_flush() {
+        let window = Dimensions::new(500, 400);
+        let map = Dimensions::new(1000, 1000);
+        assert_eq!(clamp_offset((-20.0, 5000.0), window, map, 1.0, 0.0), (0.0, 600.0));
+        assert_eq!(clamp_offset((250.5, 100.25), window, map, 1.0, 0.0), (250.5, 100.25));
+        assert_eq!(clamp_offset((-20.0, 5000.0), window, map, 1.0, 128.0), (-20.0, 728.0));
+    }
+
+    #[test]
+    fn clamp_offset_pins_a_map_smaller_than_the_window() {
+        let window = Dimensions::new(1024, 768);
+        assert_eq!(clamp_offset((37.5, -3.0), window, Dimensions::new(64, 64), 1.0, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn zoom_keeps_the_point_under_the_anchor_fixed() {
+        let (offset, anchor, zoom, new_zoom) = (100.0, 300.0, 1.0, 2.0);
+        let new_offset = zoom_anchor_offset(offset, anchor, zoom, new_zoom);
+        assert_eq!(offset + anchor / zoom, new_offset + anchor / new_zoom);
+        assert_eq!(new_offset, 250.0);
+
+        // Zooming about the window's corner leaves the offset alone
+        assert_eq!(zoom_anchor_offset(100.0, 0.0, 1.0, 4.0), 100.0);
+    }
+
+    #[test]
+    fn zooming_out_near_the_edge_is_clamped() {
+        // Zooming out about the right edge would show past the end of the map
+        let window = Dimensions::new(500, 500);
+        let map = Dimensions::new(1000, 1000);
+        let x = zoom_anchor_offset(700.0, 500.0, 2.0, 1.0);
+        assert_eq!(x, 450.0);
+        assert_eq!(clamp_offset((x, 0.0), window, map, 1.0, 0.0), (450.0, 0.0));
+        let x = zoom_anchor_offset(760.0, 500.0, 2.0, 1.0);
+        assert_eq!(clamp_offset((x, 0.0), window, map, 1.0, 0.0), (500.0, 0.0));
+    }
+}
@@ -0,0 +1,60 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use speedy2d::window::UserEventSender;
+
+use crate::viewer::MapViewEvent;
+
+// Editors and exporters often write a file in several steps, wait for them to settle
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+fn is_change_to(event: &Event, path: &Path) -> bool {
+    let is_change = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+    is_change && event.paths.iter().any(|changed| changed.file_name() == path.file_name())
+}
+
+// Sends MapViewEvent::Reload whenever the file changes. The returned watcher
+// stops watching when it is dropped.
+pub fn watch_map(
+    path: &Path,
+    event_sender: UserEventSender<MapViewEvent>,
+) -> Result<RecommendedWatcher, Box<dyn Error>> {
+    let path: PathBuf = path.canonicalize().map_err(|e| format!("Failed to watch {}: {}", path.display(), e))?;
+    let (changes, debounced) = mpsc::channel::<()>();
+
+    let watched_path = path.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) if is_change_to(&event, &watched_path) => {
+            let _ = changes.send(());
+        }
+        Ok(_) => {}
+        Err(e) => warn!("File watch error: {}", e),
+    })?;
+
+    // Watch the directory rather than the file, so that saves which replace the
+    // file via a rename are still seen
+    let directory = path.parent().unwrap_or(&path);
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        while debounced.recv().is_ok() {
+            while debounced.recv_timeout(DEBOUNCE).is_ok() {}
+
+            if event_sender.send_event(MapViewEvent::Reload).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(watcher)
+}
@@ -0,0 +1,58 @@
+// kknd2-mapview
+// Copyright (c) 2024 Matthew Costa <ucosty@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::io::{BufReader, Cursor};
+
+use kknd2_mapview::map::{load_map_from_reader, LoadOptions, Map};
+use kknd2_mapview::palette::Colour;
+use kknd2_mapview::render::{render_map, RenderOptions};
+use kknd2_mapview::test_support::{raw_map, TestLayer};
+
+fn load(data: Vec<u8>) -> Map {
+    load_map_from_reader(&mut BufReader::new(Cursor::new(data)), &LoadOptions::default()).unwrap()
+}
+
+fn rgba(packed: u16) -> [u8; 4] {
+    let colour = Colour::from_packed(packed);
+    [colour.r, colour.g, colour.b, colour.a]
+}
+
+#[test]
+fn one_layer_map_renders() {
+    let palette = [0, 0x7c00];
+    let layer = TestLayer { tile_size: (2, 2), map_size: (2, 1), cells: &[1, 0] };
+    let data = raw_map(&palette, &[layer], &[&[1, 1, 1, 1]]);
+    let map = load(data);
+    assert_eq!(map.layers.len(), 1);
+
+    let image = render_map(&map, &RenderOptions::default()).unwrap();
+    assert_eq!(image.dimensions(), (4, 2));
+    assert_eq!(image.get_pixel(1, 1).0, rgba(0x7c00));
+    assert_eq!(image.get_pixel(2, 0).0, [0, 0, 0, 0]);
+}
+
+#[test]
+fn tiles_render_in_place_over_the_background() {
+    let palette = [0, 0x7c00, 0x03e0, 0x001f];
+    let tiles: [&[u8]; 2] = [&[1, 1, 1, 1], &[1, 2, 3, 0]];
+    let layer = TestLayer { tile_size: (2, 2), map_size: (3, 2), cells: &[1, 2, 0, 0, 2, 1] };
+    let data = raw_map(&palette, &[layer], &tiles);
+    let background = (10, 20, 30);
+    let image = render_map(&load(data), &RenderOptions { background: Some(background) }).unwrap();
+    assert_eq!(image.dimensions(), (6, 4));
+
+    let background = [background.0, background.1, background.2, 0xff];
+    let expected = [
+        [rgba(0x7c00), rgba(0x7c00), rgba(0x7c00), rgba(0x03e0), background, background],
+        [rgba(0x7c00), rgba(0x7c00), rgba(0x001f), background, background, background],
+        [background, background, rgba(0x7c00), rgba(0x03e0), rgba(0x7c00), rgba(0x7c00)],
+        [background, background, rgba(0x001f), background, rgba(0x7c00), rgba(0x7c00)],
+    ];
+    for (y, row) in expected.iter().enumerate() {
+        for (x, pixel) in row.iter().enumerate() {
+            assert_eq!(&image.get_pixel(x as u32, y as u32).0, pixel, "pixel ({}, {})", x, y);
+        }
+    }
+}